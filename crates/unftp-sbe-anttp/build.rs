@@ -3,7 +3,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let protoc_exists = Command::new("protoc").arg("--version").output().is_ok();
 
     if protoc_exists {
-        tonic_build::compile_protos("proto/archive.proto")?;
+        // File contents are shared rather than copied when an update is retried
+        tonic_build::configure()
+            .bytes([".archive.File.content"])
+            .compile_protos(&["proto/archive.proto"], &["proto"])?;
         tonic_build::compile_protos("proto/public_archive.proto")?;
         tonic_build::compile_protos("proto/pointer.proto")?;
     } else {
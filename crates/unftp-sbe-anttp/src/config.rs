@@ -0,0 +1,176 @@
+use crate::merge::ConflictPolicy;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Default number of upload bytes held in memory before spooling to disk (8 MiB).
+pub const DEFAULT_UPLOAD_MEMORY_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Default limit for gRPC messages exchanged with AntTP (64 MiB). This bounds the largest file
+/// that can be downloaded or uploaded.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Default number of times a failed AntTP call is retried when AntTP is unavailable.
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+
+/// Default delay before the first retry. It doubles with every further retry.
+pub const DEFAULT_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Default upper bound on the delay between retries.
+pub const DEFAULT_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Default time allowed for establishing a connection to AntTP.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default deadline for AntTP calls that read archives or pointers.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default deadline for AntTP calls that write archives or pointers.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Default deadline for pushing an archive to the network, which can take much longer than local
+/// reads and writes.
+pub const DEFAULT_PUSH_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Where AntTP stores the archives and pointers written through the back-end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreType {
+    /// Held in AntTP's memory only, e.g. for tests.
+    Memory,
+    /// Staged on AntTP's local disk, to be pushed to the network later.
+    #[default]
+    Disk,
+    /// Written straight through to the Autonomi network.
+    Network,
+}
+
+impl FromStr for StoreType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StoreType::Memory),
+            "disk" => Ok(StoreType::Disk),
+            "network" => Ok(StoreType::Network),
+            _ => Err(format!("unknown store type '{}', expected memory, disk or network", s)),
+        }
+    }
+}
+
+impl fmt::Display for StoreType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreType::Memory => write!(f, "memory"),
+            StoreType::Disk => write!(f, "disk"),
+            StoreType::Network => write!(f, "network"),
+        }
+    }
+}
+
+/// Configuration for the AntTP storage back-end.
+#[derive(Debug, Clone)]
+pub struct AnttpConfig {
+    /// Maximum number of bytes of an upload held in memory per session. Anything beyond this is
+    /// spooled to `staging_dir` until the upload completes.
+    pub upload_memory_limit: usize,
+    /// Directory used to spool uploads that exceed `upload_memory_limit`.
+    pub staging_dir: PathBuf,
+    /// Optional upper bound on the size of a single upload, in bytes.
+    pub max_upload_size: Option<u64>,
+    /// Maximum size of a gRPC message received from AntTP, i.e. the largest file that can be downloaded.
+    pub max_decoding_message_size: usize,
+    /// Maximum size of a gRPC message sent to AntTP, i.e. the largest file that can be uploaded.
+    pub max_encoding_message_size: usize,
+    /// Where archives and pointers are written.
+    pub store_type: StoreType,
+    /// Stores that archives are read from, in order. A store is only skipped when it does not
+    /// have the requested path. Empty means reading from `store_type` alone.
+    pub read_store_types: Vec<StoreType>,
+    /// Copy archives found further down `read_store_types` into the first store, so that later
+    /// reads are served from there.
+    pub warm_reads: bool,
+    /// Compress gRPC messages exchanged with AntTP using gzip.
+    pub compression: bool,
+    /// Reject every change to the archive, so that the pointer is never updated. Suitable for
+    /// public mirrors.
+    pub read_only: bool,
+    /// Let RMD remove directories that still have contents, along with everything beneath them.
+    pub recursive_rmd: bool,
+    /// How a file changed both by us and by another writer to the same pointer is resolved.
+    pub conflict_policy: ConflictPolicy,
+    /// How often a read or archive update is retried when AntTP is unavailable or times out.
+    /// Pointer updates are never retried.
+    pub retry_attempts: u32,
+    /// Delay before the first retry, doubled for each retry after that.
+    pub retry_initial_backoff: Duration,
+    /// Upper bound on the delay between retries.
+    pub retry_max_backoff: Duration,
+    /// Time allowed for establishing a connection to AntTP.
+    pub connect_timeout: Duration,
+    /// Deadline for each call that reads an archive or pointer. Calls that run out of time are
+    /// reported to clients as transient errors.
+    pub read_timeout: Duration,
+    /// Deadline for each call that writes an archive or pointer.
+    pub write_timeout: Duration,
+    /// Deadline for pushing an archive and its pointer to the network.
+    pub push_timeout: Duration,
+}
+
+impl AnttpConfig {
+    /// The stores that archives are read from, in order.
+    pub fn read_stores(&self) -> Vec<StoreType> {
+        if self.read_store_types.is_empty() {
+            vec![self.store_type]
+        } else {
+            self.read_store_types.clone()
+        }
+    }
+}
+
+impl Default for AnttpConfig {
+    fn default() -> Self {
+        AnttpConfig {
+            upload_memory_limit: DEFAULT_UPLOAD_MEMORY_LIMIT,
+            staging_dir: std::env::temp_dir().join("antftp-staging"),
+            max_upload_size: None,
+            max_decoding_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_encoding_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            store_type: StoreType::default(),
+            read_store_types: Vec::new(),
+            warm_reads: false,
+            compression: false,
+            read_only: false,
+            recursive_rmd: false,
+            conflict_policy: ConflictPolicy::default(),
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_initial_backoff: DEFAULT_RETRY_INITIAL_BACKOFF,
+            retry_max_backoff: DEFAULT_RETRY_MAX_BACKOFF,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            push_timeout: DEFAULT_PUSH_TIMEOUT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_type_from_str() {
+        for store_type in [StoreType::Memory, StoreType::Disk, StoreType::Network] {
+            assert_eq!(store_type.to_string().parse::<StoreType>().unwrap(), store_type);
+        }
+        assert!("cloud".parse::<StoreType>().is_err());
+    }
+
+    #[test]
+    fn test_read_stores() {
+        let config = AnttpConfig { store_type: StoreType::Memory, ..Default::default() };
+        assert_eq!(config.read_stores(), vec![StoreType::Memory]);
+        let config = AnttpConfig { read_store_types: vec![StoreType::Disk, StoreType::Network], ..config };
+        assert_eq!(config.read_stores(), vec![StoreType::Disk, StoreType::Network]);
+    }
+}
@@ -1,10 +1,18 @@
-use crate::Anttp;
+use crate::{Anttp, AnttpConfig, AnttpUser, UserFile};
 use libunftp::{Server, ServerBuilder};
 
 use crate::proto::pointer::pointer_service_client::PointerServiceClient;
+use std::sync::{Arc, OnceLock};
 use tonic::transport::Channel;
 use unftp_core::auth::DefaultUser;
 
+/// Creates the back-end when the first session starts and hands every session a clone of it, so
+/// that all sessions share a single archive head and their writes are serialised against it.
+fn shared_backend(create: impl Fn() -> Anttp + Send + Sync + 'static) -> Box<dyn Fn() -> Anttp + Send + Sync> {
+    let backend = OnceLock::new();
+    Box::new(move || backend.get_or_init(&create).clone())
+}
+
 /// Extension trait purely for construction convenience.
 pub trait ServerExt {
     /// Create a new `Server` with the given AntTP address.
@@ -19,7 +27,7 @@ pub trait ServerExt {
     /// ```
     fn with_anttp(address: impl Into<String>) -> ServerBuilder<Anttp, DefaultUser> {
         let address = address.into();
-        libunftp::ServerBuilder::new(Box::new(move || {
+        libunftp::ServerBuilder::new(shared_backend(move || {
             let address = address.clone();
             Anttp::new(address).expect("Cannot connect to AntTP")
         }))
@@ -28,11 +36,41 @@ pub trait ServerExt {
     /// Create a new `Server` with the given AntTP address and pointer client.
     fn with_anttp_pointer(address: impl Into<String>, pointer_client: PointerServiceClient<Channel>, pointer_name: String) -> ServerBuilder<Anttp, DefaultUser> {
         let address = address.into();
-        libunftp::ServerBuilder::new(Box::new(move || {
+        libunftp::ServerBuilder::new(shared_backend(move || {
             let address = address.clone();
             Anttp::new_with_pointer(address, pointer_client.clone(), pointer_name.clone()).expect("Cannot connect to AntTP")
         }))
     }
+
+    /// Create a new `Server` with the given AntTP address, optional pointer name and configuration.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    /// use unftp_sbe_anttp::{AnttpConfig, ServerExt};
+    ///
+    /// let config = AnttpConfig { upload_memory_limit: 1024 * 1024, ..Default::default() };
+    /// let server = Server::with_anttp_config("some_address", None, config);
+    /// ```
+    fn with_anttp_config(address: impl Into<String>, pointer_name: Option<String>, config: AnttpConfig) -> ServerBuilder<Anttp, DefaultUser> {
+        let address = address.into();
+        libunftp::ServerBuilder::new(shared_backend(move || {
+            let address = address.clone();
+            Anttp::new_with_config(address, pointer_name.clone(), config.clone()).expect("Cannot connect to AntTP")
+        }))
+    }
+
+    /// Like [`with_anttp_config`](ServerExt::with_anttp_config), but only lets in the users listed
+    /// in `users`.
+    fn with_anttp_users(address: impl Into<String>, pointer_name: Option<String>, config: AnttpConfig, users: Arc<UserFile>) -> ServerBuilder<Anttp, AnttpUser> {
+        let address = address.into();
+        let backend = shared_backend(move || {
+            let address = address.clone();
+            Anttp::new_with_config(address, pointer_name.clone(), config.clone()).expect("Cannot connect to AntTP")
+        });
+        libunftp::ServerBuilder::with_authenticator(backend, users.clone()).user_detail_provider(users)
+    }
 }
 
 impl ServerExt for Server<Anttp, DefaultUser> {}
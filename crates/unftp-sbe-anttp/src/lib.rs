@@ -1,110 +1,690 @@
+pub mod config;
+pub mod merge;
 pub mod proto;
+mod retry;
+mod staging;
+pub mod users;
 
 use crate::proto::archive::archive_service_client::ArchiveServiceClient;
-use crate::proto::archive::{GetArchiveRequest, UpdateArchiveRequest, TruncateArchiveRequest, File};
+use crate::proto::archive::{ArchiveResponse, GetArchiveRequest, PushArchiveRequest, UpdateArchiveRequest, TruncateArchiveRequest, File, Item};
 use crate::proto::pointer::pointer_service_client::PointerServiceClient;
 use crate::proto::pointer::{UpdatePointerRequest, Pointer};
+use crate::retry::{with_deadline, with_retry};
+use crate::staging::Upload;
 use async_trait::async_trait;
+use futures::future::BoxFuture;
 use unftp_core::auth::UserDetail;
-use unftp_core::storage::{Fileinfo, Metadata, Permissions, Result, StorageBackend, Error, ErrorKind};
+use unftp_core::storage::{Fileinfo, Metadata, Permissions, Result, StorageBackend, Error, ErrorKind, FEATURE_RESTART};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
-use log::debug;
-use tokio::io::AsyncReadExt;
+use log::{debug, warn};
+use prost::Message;
+use prost::bytes::Bytes;
 use tokio::sync::RwLock;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Channel;
 
 pub mod ext;
+pub use config::{AnttpConfig, StoreType};
 pub use ext::ServerExt;
+pub use merge::ConflictPolicy;
+pub use users::{AnttpUser, AnttpUserDetail, UserArchive, UserFile, UserPermissions};
 
+/// Storage back-end serving an AntTP archive. Clones share the same archive head, so writes made
+/// through one clone are seen by all of them.
 #[derive(Debug, Clone)]
 pub struct Anttp {
     client: ArchiveServiceClient<Channel>,
     pointer_client: PointerServiceClient<Channel>,
     address: Arc<RwLock<String>>,
+    /// Serialises writes to the archive head, so reads never wait on a write
+    writer: Arc<tokio::sync::Mutex<()>>,
     pointer_name: Option<String>,
+    data_key: Option<String>,
     store_type: Option<String>,
+    config: AnttpConfig,
+    /// Archives being or already copied into the first read store
+    warmed: Arc<Mutex<HashSet<String>>>,
+    /// Back-ends serving users their own archives, shared by all sessions of those users
+    user_backends: Arc<Mutex<HashMap<UserArchive, Anttp>>>,
+    /// Home directories known to exist in the archive
+    homes: Arc<Mutex<HashSet<PathBuf>>>,
 }
 
 impl Anttp {
     pub fn new(address: String) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::new_with_config(address, None, AnttpConfig::default())
+    }
+
+    /// Creates a back-end for the given archive address (or pointer, when `pointer_name` is set)
+    /// using the supplied configuration.
+    pub fn new_with_config(address: String, pointer_name: Option<String>, config: AnttpConfig) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let endpoint = std::env::var("ANTTP_GRPC_ENDPOINT").unwrap_or_else(|_| "http://localhost:18887".to_string());
-        let channel = tonic::transport::Channel::from_shared(endpoint)?.connect_lazy();
-        let client = ArchiveServiceClient::new(channel.clone());
-        let pointer_client = PointerServiceClient::new(channel);
-        let store_type = Some("disk".to_string());
+        let channel = tonic::transport::Channel::from_shared(endpoint)?
+            .connect_timeout(config.connect_timeout)
+            .connect_lazy();
+        let client = archive_client(channel.clone(), &config);
+        let pointer_client = pointer_client(channel, &config);
+        let store_type = Some(config.store_type.to_string());
         Ok(Anttp {
             client,
             pointer_client,
             address: Arc::new(RwLock::new(address)),
-            pointer_name: None,
+            writer: Arc::default(),
+            pointer_name,
+            data_key: None,
             store_type,
+            config,
+            warmed: Arc::default(),
+            user_backends: Arc::default(),
+            homes: Arc::default(),
         })
     }
 
     pub fn new_with_pointer(address: String, pointer_client: PointerServiceClient<Channel>, pointer_name: String) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let endpoint = std::env::var("ANTTP_GRPC_ENDPOINT").unwrap_or_else(|_| "http://localhost:18887".to_string());
-        let channel = tonic::transport::Channel::from_shared(endpoint)?.connect_lazy();
-        let client = ArchiveServiceClient::new(channel);
-        let store_type = Some("disk".to_string());
+        let config = AnttpConfig::default();
+        let channel = tonic::transport::Channel::from_shared(endpoint)?
+            .connect_timeout(config.connect_timeout)
+            .connect_lazy();
+        let client = archive_client(channel, &config);
+        let store_type = Some(config.store_type.to_string());
         Ok(Anttp {
             client,
             pointer_client,
             address: Arc::new(RwLock::new(address)),
+            writer: Arc::default(),
             pointer_name: Some(pointer_name),
+            data_key: None,
             store_type,
+            config,
+            warmed: Arc::default(),
+            user_backends: Arc::default(),
+            homes: Arc::default(),
         })
     }
 
-    async fn resolve_pointer(&self) -> Result<()> {
-        if let Some(ref pointer_name) = self.pointer_name {
+    /// The back-end serving `user`: this one, or one for the user's own archive. The latter is
+    /// created on first use, and shares its archive head with all sessions mapped to that archive.
+    /// The user's home directory is created if it doesn't exist yet.
+    async fn backend_for<User: AnttpUserDetail>(&self, user: &User) -> Result<Anttp> {
+        let backend = match user.archive() {
+            None => self.clone(),
+            Some(archive) => {
+                let mut backends = self.user_backends.lock().unwrap();
+                backends.entry(archive.clone())
+                    .or_insert_with(|| Anttp {
+                        address: Arc::new(RwLock::new(archive.archive.clone().unwrap_or_default())),
+                        pointer_name: archive.pointer.clone(),
+                        data_key: archive.data_key.clone(),
+                        writer: Arc::default(),
+                        homes: Arc::default(),
+                        ..self.clone()
+                    })
+                    .clone()
+            }
+        };
+        if let Some(home) = user.home() {
+            backend.ensure_home(home).await?;
+        }
+        Ok(backend)
+    }
+
+    /// Creates `home` the first time a user confined to it logs in. Read-only archives are left
+    /// as they are, so a missing home directory stays missing.
+    async fn ensure_home(&self, home: &Path) -> Result<()> {
+        if self.config.read_only || self.homes.lock().unwrap().contains(home) {
+            return Ok(());
+        }
+        self.write(|address| Box::pin(self.mkd_at(address, home))).await?;
+        self.homes.lock().unwrap().insert(home.to_path_buf());
+        Ok(())
+    }
+
+    /// Reads the configured pointer, or returns `None` when the archive is addressed directly.
+    async fn get_pointer(&self) -> Result<Option<Pointer>> {
+        let Some(ref pointer_name) = self.pointer_name else {
+            return Ok(None);
+        };
+        let request = crate::proto::pointer::GetPointerRequest {
+            address: pointer_name.to_string(),
+            data_key: self.data_key.clone(),
+        };
+
+        let response = with_retry(&self.config, || {
             let mut client = self.pointer_client.clone();
-            let req = tonic::Request::new(crate::proto::pointer::GetPointerRequest {
-                address: pointer_name.to_string(),
-                data_key: None,
-            });
+            let request = tonic::Request::new(request.clone());
+            with_deadline(self.config.read_timeout, async move { client.get_pointer(request).await })
+        }).await;
+        match response {
+            Ok(resp) => match resp.into_inner().pointer {
+                Some(pointer) => Ok(Some(pointer)),
+                None => Err(Error::new(ErrorKind::PermanentFileNotAvailable, "Pointer not found in response")),
+            },
+            Err(e) => Err(Error::new(error_kind(&e), format!("Failed to resolve pointer '{}': {}", pointer_name, e))),
+        }
+    }
 
-            match client.get_pointer(req).await {
-                Ok(resp) => {
-                    if let Some(pointer) = resp.into_inner().pointer {
-                        let mut address = self.address.write().await;
-                        *address = pointer.content;
-                        return Ok(());
+    /// The address of the archive head to read from: the pointer content when there is a pointer,
+    /// as other servers may have moved it, and otherwise the last address we published.
+    async fn head(&self) -> Result<String> {
+        match self.get_pointer().await? {
+            Some(pointer) => Ok(pointer.content),
+            None => Ok(self.address.read().await.clone()),
+        }
+    }
+
+    /// Not retried: a retry could not tell our own earlier update apart from another writer's.
+    async fn update_pointer_with_store(&self, pointer_name: &str, new_address: String, counter: Option<u64>, store_type: Option<String>) -> std::result::Result<(), tonic::Status> {
+        let mut pointer_client = self.pointer_client.clone();
+        let request = tonic::Request::new(UpdatePointerRequest {
+            address: pointer_name.to_string(),
+            pointer: Some(Pointer {
+                name: Some(pointer_name.to_string()),
+                content: new_address,
+                address: None,
+                counter,
+                cost: None,
+            }),
+            store_type,
+            data_key: self.data_key.clone(),
+        });
+
+        with_deadline(self.config.write_timeout, pointer_client.update_pointer(request)).await?;
+        Ok(())
+    }
+
+    /// Moves the pointer from `expected`, as read before the write, to `new_address`. The counter
+    /// of the new pointer follows on from the expected one, so the update only succeeds if nobody
+    /// else moved the pointer in the meantime. Returns false when they did. A pointer without a
+    /// counter is taken to be at 0, so its first update carries a counter of 1.
+    async fn publish(&self, expected: Option<&Pointer>, new_address: String) -> Result<bool> {
+        let (Some(pointer_name), Some(expected)) = (&self.pointer_name, expected) else {
+            return Ok(true);
+        };
+        // Check up front as well, in case AntTP does not enforce the counter itself
+        let current = self.get_pointer().await?;
+        if current.as_ref().is_none_or(|current| current.content != expected.content || current.counter != expected.counter) {
+            return Ok(false);
+        }
+        let counter = Some(expected.counter.unwrap_or(0) + 1);
+        match self.update_pointer_with_store(pointer_name, new_address, counter, self.store_type.clone()).await {
+            Ok(()) => Ok(true),
+            Err(status) if matches!(status.code(), tonic::Code::Aborted | tonic::Code::FailedPrecondition | tonic::Code::AlreadyExists) => Ok(false),
+            Err(e) => Err(Error::new(error_kind(&e), format!("failed to update pointer: {}", e))),
+        }
+    }
+
+    /// Applies `op` to the archive head and publishes the address it returns. Writes are
+    /// serialised with each other, and with a pointer the head is re-read first. When another
+    /// writer moves the pointer before ours is published, our changes are merged on top of their
+    /// head rather than overwriting it.
+    async fn write<'a>(&self, op: impl FnOnce(String) -> BoxFuture<'a, Result<String>>) -> Result<()> {
+        self.check_writable()?;
+        let _writer = self.writer.lock().await;
+        let mut expected = self.get_pointer().await?;
+        let mut head = match expected {
+            Some(ref pointer) => pointer.content.clone(),
+            None => self.address.read().await.clone(),
+        };
+        // Intermediate addresses are never published; only the final archive is
+        let mut new_address = op(head.clone()).await?;
+        if new_address == head {
+            return Ok(());
+        }
+        for _ in 0..MAX_WRITE_ATTEMPTS {
+            if self.publish(expected.as_ref(), new_address.clone()).await? {
+                *self.address.write().await = new_address;
+                return Ok(());
+            }
+            debug!("Pointer moved during write, merging onto the new head");
+            let current = self.get_pointer().await?
+                .ok_or_else(|| Error::new(ErrorKind::PermanentFileNotAvailable, "Pointer not found in response"))?;
+            new_address = self.merge(&head, &new_address, &current.content).await?;
+            head = current.content.clone();
+            expected = Some(current);
+        }
+        Err(Error::new(ErrorKind::TransientFileNotAvailable, "archive pointer kept moving, giving up"))
+    }
+
+    /// Reads `path` from the archive, trying each of the configured read stores in turn until one
+    /// of them has it.
+    async fn get_archive_at(&self, address: String, path: String) -> Result<ArchiveResponse> {
+        let stores = self.config.read_stores();
+        for (i, store_type) in stores.iter().enumerate() {
+            let request = GetArchiveRequest {
+                address: address.clone(),
+                path: Some(path.clone()),
+                store_type: Some(store_type.to_string()),
+            };
+
+            let response = with_retry(&self.config, || {
+                let mut client = self.client.clone();
+                let request = tonic::Request::new(request.clone());
+                with_deadline(self.config.read_timeout, async move { client.get_archive(request).await })
+            }).await;
+            match response {
+                Err(status) if status.code() == tonic::Code::NotFound && i + 1 < stores.len() => {
+                    debug!("{} not found in the {} store, trying the next one", path, store_type);
+                }
+                response => {
+                    let response = response.map_err(status_to_error)?;
+                    if i > 0 && self.config.warm_reads {
+                        self.warm(address, stores[0]);
                     }
-                    return Err(Error::new(ErrorKind::PermanentFileNotAvailable, "Pointer not found in response"));
+                    return Ok(response.into_inner());
                 }
+            }
+        }
+        Err(Error::from(ErrorKind::PermanentFileNotAvailable))
+    }
+
+    /// Copies the archive at `address` into `store_type` in the background, so that later reads
+    /// find it there.
+    fn warm(&self, address: String, store_type: StoreType) {
+        if !self.warmed.lock().unwrap().insert(address.clone()) {
+            return;
+        }
+        let mut client = self.client.clone();
+        let warmed = self.warmed.clone();
+        let timeout = self.config.push_timeout;
+        tokio::spawn(async move {
+            let request = tonic::Request::new(PushArchiveRequest {
+                address: address.clone(),
+                store_type: Some(store_type.to_string()),
+            });
+            match with_deadline(timeout, client.push_archive(request)).await {
+                Ok(_) => debug!("Warmed archive {} into the {} store", address, store_type),
                 Err(e) => {
-                    return Err(Error::new(ErrorKind::PermanentFileNotAvailable, format!("Failed to resolve pointer '{}': {}", pointer_name, e)));
+                    warn!("Failed to warm archive {} into the {} store: {}", address, store_type, e);
+                    warmed.lock().unwrap().remove(&address);
                 }
             }
-        }
-        Ok(())
+        });
     }
 
-    async fn update_pointer_with_store(&self, new_address: String, store_type: Option<String>) -> Result<()> {
-        if let Some(ref pointer_name) = self.pointer_name {
-            let mut pointer_client = self.pointer_client.clone();
-            let request = tonic::Request::new(UpdatePointerRequest {
-                address: pointer_name.clone(),
-                pointer: Some(Pointer {
-                    name: Some(pointer_name.clone()),
-                    content: new_address,
-                    address: None,
-                    counter: None,
-                    cost: None,
-                }),
-                store_type,
-                data_key: None,
+    /// Looks `path` up in its parent directory listing, so that file content is never fetched.
+    async fn stat(&self, address: String, path: &Path) -> Result<Meta> {
+        let (parent, name) = split_path(path);
+        if is_placeholder(&name) {
+            return Err(Error::from(ErrorKind::PermanentFileNotAvailable));
+        }
+        if name.is_empty() {
+            // The archive root
+            return Ok(Meta {
+                len: 0,
+                is_dir: true,
+                modified: None,
+                read_only: self.config.read_only,
             });
+        }
+        let listing = self.get_archive_at(address, parent).await?;
+        listing.items.iter()
+            .find(|item| item.name == name)
+            .map(|item| self.meta(item))
+            .ok_or_else(|| Error::from(ErrorKind::PermanentFileNotAvailable))
+    }
+
+    fn meta(&self, item: &Item) -> Meta {
+        Meta {
+            read_only: self.config.read_only,
+            ..Meta::from(item)
+        }
+    }
+
+    /// Fails with `PermissionDenied` unless `allowed`.
+    fn require(allowed: bool, action: &str) -> Result<()> {
+        if !allowed {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("not allowed to {}", action)));
+        }
+        Ok(())
+    }
 
-            pointer_client.update_pointer(request).await.map_err(|e| {
-                Error::new(ErrorKind::PermanentFileNotAvailable, format!("failed to update pointer: {}", e))
-            })?;
+    /// Fails every change to the archive when the back-end is read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.config.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "the archive is read-only"));
         }
         Ok(())
     }
+
+    /// Walks down to `path`, failing if one of its ancestors is a file. Returns the deepest
+    /// directory on the way that already exists, along with the item at `path` if there is one.
+    async fn locate(&self, address: &str, path: &Path) -> Result<(String, Option<Item>)> {
+        let names: Vec<String> = path.components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        let mut dir = if path.has_root() { "/".to_string() } else { String::new() };
+        for (i, name) in names.iter().enumerate() {
+            let listing = self.get_archive_at(address.to_string(), dir.clone()).await?;
+            let Some(item) = listing.items.into_iter().find(|item| &item.name == name) else {
+                return Ok((dir, None));
+            };
+            if i == names.len() - 1 {
+                return Ok((dir, Some(item)));
+            }
+            if !is_directory(&item) {
+                return Err(Error::new(ErrorKind::FileNameNotAllowedError, format!("{} is a file", join_path(&dir, name))));
+            }
+            dir = join_path(&dir, name);
+        }
+        Ok((dir, None))
+    }
+
+    /// Adds `files` to the directory at `path`, returning the new archive address. Archive updates
+    /// only create a new address and never change an existing one, so they are safe to retry.
+    async fn update_archive_at(&self, address: String, path: String, files: Vec<File>) -> Result<String> {
+        let request = UpdateArchiveRequest {
+            address: address.clone(),
+            files,
+            path: Some(path),
+            store_type: self.store_type.clone(),
+        };
+        // tonic reports oversized requests as an opaque transport error, so check up front
+        if request.encoded_len() > self.config.max_encoding_message_size {
+            return Err(Error::new(ErrorKind::ExceededStorageAllocationError, "file exceeds the configured gRPC message size limit"));
+        }
+
+        let response = with_retry(&self.config, || {
+            let mut client = self.client.clone();
+            let request = tonic::Request::new(request.clone());
+            with_deadline(self.config.write_timeout, async move { client.update_archive(request).await })
+        }).await.map_err(status_to_error)?;
+        Ok(response.into_inner().address.unwrap_or(address))
+    }
+
+    /// Removes the file or directory at `path`, returning the new archive address.
+    async fn truncate_archive_at(&self, address: String, path: String) -> Result<String> {
+        let request = TruncateArchiveRequest {
+            address: address.clone(),
+            path,
+            store_type: self.store_type.clone(),
+        };
+
+        let response = with_retry(&self.config, || {
+            let mut client = self.client.clone();
+            let request = tonic::Request::new(request.clone());
+            with_deadline(self.config.write_timeout, async move { client.truncate_archive(request).await })
+        }).await.map_err(status_to_error)?;
+        Ok(response.into_inner().address.unwrap_or(address))
+    }
+
+    /// Lists every file beneath the directory at `path`, as directory and name relative to `path`.
+    async fn collect_files(&self, address: &str, path: &str) -> Result<Vec<(String, String)>> {
+        let mut files = Vec::new();
+        let mut pending = vec![String::new()];
+        while let Some(relative_dir) = pending.pop() {
+            let dir = join_path(path, &relative_dir);
+            let listing = self.get_archive_at(address.to_string(), dir.clone()).await?;
+            for item in listing.items {
+                let relative_path = join_path(&relative_dir, &item.name);
+                if is_directory(&item) {
+                    pending.push(relative_path);
+                } else {
+                    files.push((relative_dir.clone(), item.name));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Removes the placeholder of the directory at `dir` once it holds real entries.
+    async fn drop_placeholder(&self, address: String, dir: &str) -> Result<String> {
+        let listing = self.get_archive_at(address.clone(), dir.to_string()).await?;
+        if listing.items.len() > 1 && listing.items.iter().any(|item| is_placeholder(&item.name)) {
+            return self.truncate_archive_at(address, join_path(dir, PLACEHOLDER_NAME)).await;
+        }
+        Ok(address)
+    }
+
+    /// Archives have no empty directories, so removing the last entry of `dir` removes `dir` too.
+    /// Puts a placeholder back in that case, judging from the listing at `before`, the address
+    /// prior to the removal.
+    async fn keep_directory(&self, before: String, address: String, dir: &str) -> Result<String> {
+        if is_root(dir) {
+            return Ok(address);
+        }
+        let listing = self.get_archive_at(before, dir.to_string()).await?;
+        if listing.items.len() > 1 {
+            return Ok(address);
+        }
+        self.update_archive_at(address, dir.to_string(), vec![placeholder()]).await
+    }
+
+    /// Writes the upload to `path`, returning the new archive address. An existing file is only
+    /// replaced with the `overwrite` permission, and missing parents only created with `mkdir`.
+    async fn put_at(&self, address: String, path: &Path, upload: &Upload, start_pos: u64, permissions: UserPermissions) -> Result<String> {
+        let (dir, filename) = split_path(path);
+        // Missing parent directories are created implicitly by the update
+        let (existing_dir, target) = self.locate(&address, path).await?;
+        if target.as_ref().is_some_and(is_directory) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "a directory with this name already exists"));
+        }
+        Self::require(permissions.overwrite || target.is_none(), "overwrite files")?;
+        Self::require(permissions.mkdir || Path::new(&existing_dir) == Path::new(&dir), "create directories")?;
+        let received = upload.to_bytes().await?;
+        let content = if start_pos > 0 {
+            // Resume or append: keep the existing content up to the restart offset
+            let existing = self.get_archive_at(address.clone(), path_to_string(path)).await?;
+            let mut content = existing.content.ok_or_else(|| Error::from(ErrorKind::PermanentFileNotAvailable))?;
+            if start_pos > content.len() as u64 {
+                return Err(Error::new(ErrorKind::PermanentFileNotAvailable, "restart offset is beyond the end of the file"));
+            }
+            content.truncate(start_pos as usize);
+            content.extend_from_slice(&received);
+            content.into()
+        } else {
+            received
+        };
+        let new_address = self.update_archive_at(address, dir, vec![File {
+            name: filename,
+            content,
+        }]).await?;
+        self.drop_placeholder(new_address, &existing_dir).await
+    }
+
+    /// Removes the file at `path`, returning the new archive address.
+    async fn del_at(&self, address: String, path: &Path) -> Result<String> {
+        let (parent, _) = split_path(path);
+        if self.stat(address.clone(), path).await?.is_dir {
+            return Err(Error::new(ErrorKind::PermanentFileNotAvailable, "not a file"));
+        }
+        let new_address = self.truncate_archive_at(address.clone(), path_to_string(path)).await?;
+        self.keep_directory(address, new_address, &parent).await
+    }
+
+    /// Removes the directory at `path`, returning the new archive address.
+    async fn rmd_at(&self, address: String, path: &Path) -> Result<String> {
+        let (parent, _) = split_path(path);
+        let path_str = path_to_string(path);
+        let meta = self.stat(address.clone(), path).await
+            .map_err(directory_error)?;
+        if !meta.is_dir {
+            return Err(Error::new(ErrorKind::PermanentDirectoryNotAvailable, "not a directory"));
+        }
+        if !self.config.recursive_rmd {
+            let listing = self.get_archive_at(address.clone(), path_str.clone()).await?;
+            if listing.items.iter().any(|item| !is_placeholder(&item.name)) {
+                return Err(Error::from(ErrorKind::PermanentDirectoryNotEmpty));
+            }
+        }
+        // A single truncate removes the whole subtree in one archive revision
+        let new_address = self.truncate_archive_at(address.clone(), path_str).await?;
+        self.keep_directory(address, new_address, &parent).await
+    }
+
+    /// Creates the directory at `path`, returning the new archive address.
+    async fn mkd_at(&self, address: String, path: &Path) -> Result<String> {
+        // Like `mkdir -p`, missing parents are created along the way and an existing directory is
+        // left as it is
+        let (existing_dir, target) = self.locate(&address, path).await?;
+        match target {
+            Some(item) if is_directory(&item) => return Ok(address),
+            Some(_) => return Err(Error::new(ErrorKind::FileNameNotAllowedError, "a file with this name already exists")),
+            None => {}
+        }
+        let new_address = self.update_archive_at(address, path_to_string(path), vec![placeholder()]).await?;
+        self.drop_placeholder(new_address, &existing_dir).await
+    }
+
+    /// Moves the file or directory at `from` to `to`, returning the new archive address. An
+    /// existing file at `to` is only replaced with the `overwrite` permission, and missing parents
+    /// of `to` only created with `mkdir`.
+    async fn rename_at(&self, address: String, from: &Path, to: &Path, permissions: UserPermissions) -> Result<String> {
+        let from_str = path_to_string(from);
+        let to_str = path_to_string(to);
+        let (from_dir, _) = split_path(from);
+        let (to_dir, to_name) = split_path(to);
+        if to_name.is_empty() {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "cannot rename to the root"));
+        }
+        let mut new_address = address.clone();
+        let source = self.get_archive_at(new_address.clone(), from_str.clone()).await?;
+        // Fails on a file ancestor of the target
+        let (existing_dir, target) = self.locate(&address, to).await?;
+        Self::require(permissions.mkdir || Path::new(&existing_dir) == Path::new(&to_dir), "create directories")?;
+        if let Some(target) = target {
+            if is_directory(&target) {
+                return Err(Error::new(ErrorKind::FileNameNotAllowedError, "a directory with this name already exists"));
+            }
+            if source.content.is_none() {
+                return Err(Error::new(ErrorKind::FileNameNotAllowedError, "a file with this name already exists"));
+            }
+            Self::require(permissions.overwrite, "overwrite files")?;
+        }
+        if let Some(content) = source.content {
+            new_address = self.update_archive_at(new_address, to_dir.clone(), vec![File {
+                name: to_name,
+                content: content.into(),
+            }]).await?;
+        } else {
+            // One file per update, fetched just before it is sent, so neither memory use nor the
+            // message size grows with the directory
+            for (relative_dir, name) in self.collect_files(&address, &from_str).await? {
+                let path = join_path(&join_path(&from_str, &relative_dir), &name);
+                let content = self.get_archive_at(address.clone(), path).await?.content.unwrap_or_default();
+                new_address = self.update_archive_at(new_address, join_path(&to_str, &relative_dir), vec![File {
+                    name,
+                    content: content.into(),
+                }]).await?;
+            }
+        }
+        new_address = self.truncate_archive_at(new_address, from_str).await?;
+        if from_dir != to_dir {
+            new_address = self.keep_directory(address, new_address, &from_dir).await?;
+        }
+        self.drop_placeholder(new_address, &to_dir).await
+    }
+}
+
+fn archive_client(channel: Channel, config: &AnttpConfig) -> ArchiveServiceClient<Channel> {
+    let client = ArchiveServiceClient::new(channel)
+        .max_decoding_message_size(config.max_decoding_message_size)
+        .max_encoding_message_size(config.max_encoding_message_size);
+    if config.compression {
+        client.send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip)
+    } else {
+        client
+    }
+}
+
+fn pointer_client(channel: Channel, config: &AnttpConfig) -> PointerServiceClient<Channel> {
+    let client = PointerServiceClient::new(channel);
+    if config.compression {
+        client.send_compressed(CompressionEncoding::Gzip).accept_compressed(CompressionEncoding::Gzip)
+    } else {
+        client
+    }
+}
+
+/// Converts a failed AntTP call into a storage error.
+fn status_to_error(status: tonic::Status) -> Error {
+    match status.code() {
+        tonic::Code::NotFound => Error::from(ErrorKind::PermanentFileNotAvailable),
+        // Raised by tonic when a message exceeds the configured encode/decode limits
+        tonic::Code::OutOfRange => Error::new(
+            ErrorKind::ExceededStorageAllocationError,
+            format!("file exceeds the configured gRPC message size limit: {}", status.message()),
+        ),
+        _ => Error::new(error_kind(&status), status),
+    }
+}
+
+/// The FTP error kind for a gRPC status. Failures that may go away by themselves, such as AntTP
+/// restarting or being slow to answer, are transient so that clients know to try again.
+fn error_kind(status: &tonic::Status) -> ErrorKind {
+    match status.code() {
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::Aborted | tonic::Code::Cancelled => ErrorKind::TransientFileNotAvailable,
+        tonic::Code::Internal | tonic::Code::Unknown | tonic::Code::DataLoss => ErrorKind::LocalError,
+        tonic::Code::ResourceExhausted => ErrorKind::InsufficientStorageSpaceError,
+        tonic::Code::OutOfRange => ErrorKind::ExceededStorageAllocationError,
+        tonic::Code::PermissionDenied | tonic::Code::Unauthenticated => ErrorKind::PermissionDenied,
+        tonic::Code::InvalidArgument => ErrorKind::FileNameNotAllowedError,
+        tonic::Code::Unimplemented => ErrorKind::CommandNotImplemented,
+        _ => ErrorKind::PermanentFileNotAvailable,
+    }
+}
+
+/// Reports a missing path as a missing directory, leaving other failures as they are.
+fn directory_error(error: Error) -> Error {
+    if error.kind() == ErrorKind::PermanentFileNotAvailable {
+        Error::from(ErrorKind::PermanentDirectoryNotAvailable)
+    } else {
+        error
+    }
+}
+
+/// Resolves `.` and `..` in a client path and places it in the user's home directory, if they
+/// have one. As in a chroot, `..` never leads above the root the user sees.
+fn user_path<User: UserDetail>(user: &User, path: &Path) -> PathBuf {
+    let mut path_in_root = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => path_in_root.push(name),
+            Component::ParentDir => {
+                path_in_root.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    let root = user_root(user);
+    if path_in_root.as_os_str().is_empty() { root.to_path_buf() } else { root.join(path_in_root) }
+}
+
+/// The directory a user sees as the root: their home directory, or else the archive root.
+fn user_root<User: UserDetail>(user: &User) -> &Path {
+    user.home().unwrap_or(Path::new("/"))
+}
+
+fn path_to_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Splits a path into its parent directory and file name, as expected by `UpdateArchiveRequest`.
+fn split_path(path: &Path) -> (String, String) {
+    let parent = path.parent().unwrap_or(Path::new("")).to_str().unwrap_or_default().to_string();
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    (parent, name)
+}
+
+fn is_root(path: &str) -> bool {
+    matches!(path.trim_matches('/'), "" | ".")
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if name.is_empty() {
+        dir.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
 }
 
 #[derive(Debug)]
@@ -112,228 +692,216 @@ pub struct Meta {
     len: u64,
     is_dir: bool,
     modified: Option<SystemTime>,
+    read_only: bool,
 }
 
-const DIRECTORY_STR: &'static str = "DIRECTORY";
+impl From<&Item> for Meta {
+    fn from(item: &Item) -> Self {
+        Meta {
+            len: item.size,
+            is_dir: is_directory(item),
+            modified: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(item.modified)),
+            read_only: false,
+        }
+    }
+}
+
+const DIRECTORY_STR: &str = "DIRECTORY";
+
+/// How often a write is merged onto a new head when other writers keep moving the pointer.
+const MAX_WRITE_ATTEMPTS: usize = 5;
+
+/// MKD creates a directory by uploading this file into it, since archives can't hold empty
+/// directories. It is never shown to clients.
+const PLACEHOLDER_NAME: &str = ".metadata";
+const PLACEHOLDER_CONTENT: &[u8] = b"pad";
+
+fn placeholder() -> File {
+    File {
+        name: PLACEHOLDER_NAME.to_string(),
+        content: Bytes::from_static(PLACEHOLDER_CONTENT),
+    }
+}
+
+fn is_placeholder(name: &str) -> bool {
+    name == PLACEHOLDER_NAME
+}
+
+/// Directories are identified by their type in the parent listing. Their contents can't be used,
+/// as a directory may be empty.
+fn is_directory(item: &Item) -> bool {
+    item.r#type.eq_ignore_ascii_case(DIRECTORY_STR)
+}
 
 #[async_trait]
-impl<User: UserDetail> StorageBackend<User> for Anttp {
+impl<User: AnttpUserDetail> StorageBackend<User> for Anttp {
     type Metadata = Meta;
 
     fn supported_features(&self) -> u32 {
-        0
+        FEATURE_RESTART
     }
 
-    async fn metadata<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P) -> Result<Self::Metadata> {
+    async fn metadata<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<Self::Metadata> {
         debug!("FTP command: METADATA for path {:?}", path.as_ref());
-        self.resolve_pointer().await?;
-        let mut path_str = path.as_ref().to_string_lossy().into_owned();
-        if path_str == "." {
-            path_str = "".to_string();
-        }
-        let mut client = self.client.clone();
-        let address = self.address.read().await.clone();
-        let request = tonic::Request::new(GetArchiveRequest {
-            address,
-            path: Some(path_str),
-            store_type: self.store_type.clone(),
-        });
-
-        let response = client.get_archive(request).await.map_err(|e| {
-            if e.code() == tonic::Code::NotFound {
-                Error::from(ErrorKind::PermanentFileNotAvailable)
-            } else {
-                Error::new(ErrorKind::PermanentFileNotAvailable, e)
-            }
-        })?;
-        
-        let inner = response.into_inner();
-        
-        Ok(Meta {
-            len: inner.content.as_ref().map(|c| c.len() as u64).unwrap_or(0),
-            is_dir: !inner.items.is_empty(),
-            modified: None, // GetPublicArchiveResponse doesn't have a top-level modified date yet
-        })
+        // APPE takes the file size from here, and would truncate the file if it were refused
+        let permissions = user.permissions();
+        Self::require(permissions.read || permissions.overwrite, "read")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        let address = backend.head().await?;
+        backend.stat(address, &path).await
     }
 
-    async fn list<P>(&self, _user: &User, path: P) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
+    async fn list<P>(&self, user: &User, path: P) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
     where
         P: AsRef<Path> + Send + Debug,
     {
         debug!("FTP command: LIST for path {:?}", path.as_ref());
-        self.resolve_pointer().await?;
-        let path_str = path.as_ref().to_string_lossy().into_owned();
-        let mut client = self.client.clone();
-        let address = self.address.read().await.clone();
-        let request = tonic::Request::new(GetArchiveRequest {
-            address,
-            path: Some(path_str),
-            store_type: self.store_type.clone(),
-        });
+        Self::require(user.permissions().read, "list directories")?;
+        let backend = self.backend_for(user).await?;
+        let path_str = path_to_string(&user_path(user, path.as_ref()));
+        let address = backend.head().await?;
+        let inner = backend.get_archive_at(address, path_str).await?;
 
-        let response = client.get_archive(request).await.map_err(|e| {
-            if e.code() == tonic::Code::NotFound {
-                Error::from(ErrorKind::PermanentFileNotAvailable)
-            } else {
-                Error::new(ErrorKind::PermanentFileNotAvailable, e)
-            }
-        })?;
-        
-        let inner = response.into_inner();
         let mut fis = Vec::new();
-        for item in inner.items {
-            let item_path = PathBuf::from(&item.name);
+        for item in inner.items.into_iter().filter(|item| !is_placeholder(&item.name)) {
             fis.push(Fileinfo {
-                path: item_path,
-                metadata: Meta {
-                    len: item.size,
-                    is_dir: item.r#type.to_uppercase() == DIRECTORY_STR,
-                    modified: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(item.modified)),
-                },
+                path: PathBuf::from(&item.name),
+                metadata: backend.meta(&item),
             });
         }
 
         Ok(fis)
     }
 
-    async fn get<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P, _start_pos: u64) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
-        debug!("FTP command: GET for path {:?}", path.as_ref());
-        self.resolve_pointer().await?;
-        let path_str = path.as_ref().to_string_lossy().into_owned();
-        let mut client = self.client.clone();
-        let address = self.address.read().await.clone();
-        let request = tonic::Request::new(GetArchiveRequest {
-            address,
-            path: Some(path_str),
-            store_type: self.store_type.clone(),
-        });
-
-        let response = client.get_archive(request).await.map_err(|e| {
-            if e.code() == tonic::Code::NotFound {
-                Error::from(ErrorKind::PermanentFileNotAvailable)
-            } else {
-                Error::new(ErrorKind::PermanentFileNotAvailable, e)
-            }
-        })?;
-        
-        let inner = response.into_inner();
+    async fn get<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P, start_pos: u64) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        debug!("FTP command: GET for path {:?} from offset {}", path.as_ref(), start_pos);
+        Self::require(user.permissions().read, "download files")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        let (_, name) = split_path(&path);
+        if is_placeholder(&name) {
+            return Err(Error::from(ErrorKind::PermanentFileNotAvailable));
+        }
+        let path_str = path_to_string(&path);
+        let address = backend.head().await?;
+        // GetArchive is a unary call without range parameters, so the whole file has to arrive from
+        // AntTP before the transfer can start. Switch to a streaming or ranged read once AntTP has one.
+        let inner = backend.get_archive_at(address, path_str).await?;
         let content = inner.content.ok_or_else(|| Error::from(ErrorKind::PermanentFileNotAvailable))?;
-        
-        Ok(Box::new(std::io::Cursor::new(content)) as Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>)
+        if start_pos > content.len() as u64 {
+            return Err(Error::new(ErrorKind::PermanentFileNotAvailable, "restart offset is beyond the end of the file"));
+        }
+
+        let mut cursor = std::io::Cursor::new(content);
+        cursor.set_position(start_pos);
+        Ok(Box::new(cursor) as Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>)
     }
 
     async fn put<P: AsRef<Path> + Send, R: tokio::io::AsyncRead + Send + Sync + 'static + Unpin>(
         &self,
-        _user: &User,
-        mut bytes: R,
+        user: &User,
+        bytes: R,
         path: P,
-        _start_pos: u64,
+        start_pos: u64,
     ) -> Result<u64> {
-        debug!("FTP command: PUT for path {:?}", path.as_ref());
-        let mut content = Vec::new();
-        bytes.read_to_end(&mut content).await
-            .map_err(|e| Error::new(ErrorKind::LocalError, e))?;
-        let len = content.len() as u64;
-
-        let path_ref = path.as_ref();
-        let path_str = path_ref.parent().unwrap_or(Path::new("")).to_str().unwrap_or_default().to_string();
-        let filename = path_ref.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
-        let mut client = self.client.clone();
-
-        let mut address_guard = self.address.write().await;
-        let request = tonic::Request::new(UpdateArchiveRequest {
-            address: address_guard.clone(),
-            files: vec![File {
-                name: filename,
-                content,
-            }],
-            path: Some(path_str),
-            store_type: self.store_type.clone(),
-        });
-
-        let response = client.update_archive(request).await
-            .map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))?;
-
-        if let Some(new_address) = response.into_inner().address {
-            *address_guard = new_address.clone();
-            drop(address_guard);
-            // Update pointer to point to the new archive address if configured
-            self.update_pointer_with_store(new_address, self.store_type.clone()).await?;
-        } else {
-            drop(address_guard);
+        debug!("FTP command: PUT for path {:?} from offset {}", path.as_ref(), start_pos);
+        self.check_writable()?;
+        let permissions = user.permissions();
+        Self::require(permissions.upload, "upload files")?;
+        Self::require(permissions.overwrite || start_pos == 0, "append to files")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        let (_, filename) = split_path(&path);
+        if is_placeholder(&filename) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "reserved file name"));
         }
+        // The whole file goes out in one message, so stop receiving once it can no longer fit
+        let message_limit = (backend.config.max_encoding_message_size as u64).saturating_sub(start_pos);
+        let upload = Upload::receive(bytes, &backend.config, message_limit).await?;
+        let len = upload.len();
+
+        backend.write(|address| Box::pin(backend.put_at(address, &path, &upload, start_pos, permissions))).await?;
 
         Ok(len)
     }
 
-    async fn del<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P) -> Result<()> {
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
         debug!("FTP command: DEL for path {:?}", path.as_ref());
-        let path_str = path.as_ref().to_string_lossy().into_owned();
-        let mut client = self.client.clone();
-        let mut address_guard = self.address.write().await;
-
-        let request = tonic::Request::new(TruncateArchiveRequest {
-            address: address_guard.clone(),
-            path: path_str,
-            store_type: self.store_type.clone(),
-        });
-
-        let response = client.truncate_archive(request).await
-            .map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))?;
-
-        if let Some(new_address) = response.into_inner().address {
-            *address_guard = new_address.clone();
-            drop(address_guard);
-            // Update pointer to point to the new archive address if configured
-            self.update_pointer_with_store(new_address, self.store_type.clone()).await?;
-        } else {
-            drop(address_guard);
+        self.check_writable()?;
+        Self::require(user.permissions().delete, "delete files")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        let (_, name) = split_path(&path);
+        if is_placeholder(&name) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "directory placeholders cannot be deleted"));
         }
-
-        Ok(())
+        backend.write(|address| Box::pin(backend.del_at(address, &path))).await
     }
 
-    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P) -> Result<()> {
-        self.del(_user, path).await
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        debug!("FTP command: RMD for path {:?}", path.as_ref());
+        self.check_writable()?;
+        Self::require(user.permissions().delete, "remove directories")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        if path == user_root(user) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "cannot remove the root directory"));
+        }
+        backend.write(|address| Box::pin(backend.rmd_at(address, &path))).await
     }
 
-    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, path: P) -> Result<()> {
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
         debug!("FTP command: MKD for path {:?}", path.as_ref());
-        let path_str = path.as_ref().to_string_lossy().into_owned();
-        
-        let mut client = self.client.clone();
-        let mut address_guard = self.address.write().await;
-        
-        let request = tonic::Request::new(UpdateArchiveRequest {
-            address: address_guard.clone(),
-            files: vec![File {
-                name: ".metadata".to_string(),
-                content: "pad".as_bytes().to_vec(),
-            }],
-            path: Some(path_str),
-            store_type: self.store_type.clone(),
-        });
-
-        let response = client.update_archive(request).await
-            .map_err(|e| Error::new(ErrorKind::PermanentFileNotAvailable, e))?;
-        
-        if let Some(new_address) = response.into_inner().address {
-            *address_guard = new_address.clone();
-            drop(address_guard);
-            // Update pointer to point to the new archive address if configured
-            self.update_pointer_with_store(new_address, self.store_type.clone()).await?;
-        } else {
-            drop(address_guard);
+        self.check_writable()?;
+        Self::require(user.permissions().mkdir, "create directories")?;
+        let backend = self.backend_for(user).await?;
+        let path = user_path(user, path.as_ref());
+        if path == user_root(user) {
+            return Ok(());
         }
-
-        Ok(())
+        let (_, name) = split_path(&path);
+        if is_placeholder(&name) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "reserved file name"));
+        }
+        backend.write(|address| Box::pin(backend.mkd_at(address, &path))).await
     }
 
-    async fn rename<P: AsRef<Path> + Send + Debug>(&self, _user: &User, _from: P, _to: P) -> Result<()> {
-        Err(Error::from(ErrorKind::CommandNotImplemented))
+    async fn rename<P: AsRef<Path> + Send + Debug>(&self, user: &User, from: P, to: P) -> Result<()> {
+        debug!("FTP command: RENAME from {:?} to {:?}", from.as_ref(), to.as_ref());
+        self.check_writable()?;
+        // A rename removes the file under its old name and adds it under the new one
+        let permissions = user.permissions();
+        Self::require(permissions.delete && permissions.upload, "rename files")?;
+        let backend = self.backend_for(user).await?;
+        let from = user_path(user, from.as_ref());
+        let to = user_path(user, to.as_ref());
+        if from == to {
+            return Ok(());
+        }
+        if to == user_root(user) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "cannot rename to the root"));
+        }
+        if to.starts_with(&from) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "cannot move a directory into itself"));
+        }
+        let (_, from_name) = split_path(&from);
+        let (_, to_name) = split_path(&to);
+        if is_placeholder(&from_name) || is_placeholder(&to_name) {
+            return Err(Error::new(ErrorKind::FileNameNotAllowedError, "reserved file name"));
+        }
+        backend.write(|address| Box::pin(backend.rename_at(address, &from, &to, permissions))).await
     }
 
-    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, _user: &User, _path: P) -> Result<()> {
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        debug!("FTP command: CWD for path {:?}", path.as_ref());
+        let backend = self.backend_for(user).await?;
+        let address = backend.head().await?;
+        let meta = backend.stat(address, &user_path(user, path.as_ref())).await
+            .map_err(directory_error)?;
+        if !meta.is_dir {
+            return Err(Error::new(ErrorKind::PermanentDirectoryNotAvailable, "not a directory"));
+        }
         Ok(())
     }
 }
@@ -372,8 +940,9 @@ impl Metadata for Meta {
         1
     }
 
+    /// Read-only archives are listed without write permissions.
     fn permissions(&self) -> Permissions {
-        Permissions(0o7755)
+        if self.read_only { Permissions(0o7555) } else { Permissions(0o7755) }
     }
 
     fn readlink(&self) -> Option<&Path> {
@@ -401,6 +970,7 @@ mod tests {
             len: 100,
             is_dir: true,
             modified: Some(now),
+            read_only: false,
         };
         assert_eq!(meta.len(), 100);
         assert!(meta.is_dir());
@@ -423,12 +993,192 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_split_and_join_path() {
+        assert_eq!(split_path(Path::new("/dir/file.txt")), ("/dir".to_string(), "file.txt".to_string()));
+        assert_eq!(split_path(Path::new("/file.txt")), ("/".to_string(), "file.txt".to_string()));
+        assert_eq!(join_path("/", "file.txt"), "/file.txt");
+        assert_eq!(join_path("/dir", "sub"), "/dir/sub");
+        assert_eq!(join_path("/dir", ""), "/dir");
+        assert_eq!(join_path("", "sub"), "sub");
+    }
+
+    #[tokio::test]
+    async fn test_rename_into_itself_rejected() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let user = unftp_core::auth::DefaultUser {};
+        let result: Result<()> = anttp.rename(&user, "/dir", "/dir/sub").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::FileNameNotAllowedError);
+        let result: Result<()> = anttp.rename(&user, "/dir", "/dir").await;
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rename_to_root_rejected() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let result = anttp.rename_at("some_address".to_string(), Path::new("/a"), Path::new("/"), UserPermissions::ALL).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::FileNameNotAllowedError);
+    }
+
+    #[tokio::test]
+    async fn test_supports_restart() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let features = <Anttp as StorageBackend<unftp_core::auth::DefaultUser>>::supported_features(&anttp);
+        assert_eq!(features & FEATURE_RESTART, FEATURE_RESTART);
+    }
+
+    #[test]
+    fn test_meta_from_item() {
+        let item = Item {
+            name: "file.txt".to_string(),
+            modified: 1_700_000_000,
+            size: 42,
+            r#type: "file".to_string(),
+        };
+        let meta = Meta::from(&item);
+        assert_eq!(meta.len(), 42);
+        assert!(meta.is_file());
+        assert_eq!(meta.modified().unwrap(), SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000));
+    }
+
+    #[test]
+    fn test_is_directory() {
+        let mut item = Item {
+            name: "dir".to_string(),
+            modified: 0,
+            size: 0,
+            r#type: "directory".to_string(),
+        };
+        assert!(is_directory(&item));
+        item.r#type = "DIRECTORY".to_string();
+        assert!(is_directory(&item));
+        item.r#type = "file".to_string();
+        assert!(!is_directory(&item));
+    }
+
+    #[test]
+    fn test_is_root() {
+        assert!(is_root("/"));
+        assert!(is_root(""));
+        assert!(is_root("."));
+        assert!(!is_root("/dir"));
+    }
+
+    #[tokio::test]
+    async fn test_placeholder_protected() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let user = unftp_core::auth::DefaultUser {};
+        let result: Result<()> = anttp.del(&user, "/dir/.metadata").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result: Result<()> = anttp.rename(&user, "/dir/.metadata", "/dir/file.txt").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::FileNameNotAllowedError);
+        let result: Result<()> = anttp.mkd(&user, "/dir/.metadata").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::FileNameNotAllowedError);
+        let result: Result<Meta> = anttp.stat("some_address".to_string(), Path::new("/dir/.metadata")).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermanentFileNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn test_rmd_root_rejected() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let user = unftp_core::auth::DefaultUser {};
+        let result: Result<()> = anttp.rmd(&user, "/").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_root_is_directory() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let meta = anttp.stat("some_address".to_string(), Path::new("/")).await.unwrap();
+        assert!(meta.is_dir());
+    }
+
+    #[test]
+    fn test_status_to_error() {
+        let error = status_to_error(tonic::Status::out_of_range("Error, decoded message length too large"));
+        assert_eq!(error.kind(), ErrorKind::ExceededStorageAllocationError);
+        let error = status_to_error(tonic::Status::not_found("missing"));
+        assert_eq!(error.kind(), ErrorKind::PermanentFileNotAvailable);
+        let error = status_to_error(tonic::Status::unavailable("restarting"));
+        assert_eq!(error.kind(), ErrorKind::TransientFileNotAvailable);
+        let error = status_to_error(tonic::Status::deadline_exceeded("slow"));
+        assert_eq!(error.kind(), ErrorKind::TransientFileNotAvailable);
+        let error = status_to_error(tonic::Status::resource_exhausted("quota"));
+        assert_eq!(error.kind(), ErrorKind::InsufficientStorageSpaceError);
+        let error = status_to_error(tonic::Status::permission_denied("denied"));
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        let error = status_to_error(tonic::Status::invalid_argument("bad path"));
+        assert_eq!(error.kind(), ErrorKind::FileNameNotAllowedError);
+    }
+
+    #[test]
+    fn test_directory_error() {
+        let error = directory_error(Error::from(ErrorKind::PermanentFileNotAvailable));
+        assert_eq!(error.kind(), ErrorKind::PermanentDirectoryNotAvailable);
+        let error = directory_error(Error::from(ErrorKind::TransientFileNotAvailable));
+        assert_eq!(error.kind(), ErrorKind::TransientFileNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_changes() {
+        let config = AnttpConfig { read_only: true, ..Default::default() };
+        let anttp = Anttp::new_with_config("some_address".to_string(), Some("pointer".to_string()), config).unwrap();
+        let user = unftp_core::auth::DefaultUser {};
+        let result: Result<()> = anttp.del(&user, "/file.txt").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result: Result<()> = anttp.mkd(&user, "/").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result: Result<()> = anttp.rename(&user, "/a", "/a").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let meta = anttp.stat("some_address".to_string(), Path::new("/")).await.unwrap();
+        assert_eq!(meta.permissions().0 & 0o222, 0);
+    }
+
+    #[tokio::test]
+    async fn test_permissions_enforced() {
+        let anttp = Anttp::new("some_address".to_string()).unwrap();
+        let user = AnttpUser { username: "bot".to_string(), permissions: UserPermissions::NONE, archive: None, home: None };
+        let result: Result<Vec<Fileinfo<PathBuf, Meta>>> = anttp.list(&user, "/").await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        let result = anttp.get(&user, "/file.txt", 0).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        let result = anttp.put(&user, b"content".as_slice(), "/file.txt", 0).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result = anttp.del(&user, "/file.txt").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result = anttp.mkd(&user, "/dir").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let user = AnttpUser { permissions: UserPermissions { upload: true, ..UserPermissions::NONE }, ..user };
+        let result = anttp.rename(&user, "/a", "/b").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        let result = anttp.put(&user, b"content".as_slice(), "/file.txt", 3).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_user_path() {
+        let user = unftp_core::auth::DefaultUser {};
+        assert_eq!(user_path(&user, Path::new("/dir/../file.txt")), Path::new("/file.txt"));
+        assert_eq!(user_path(&user, Path::new("/..")), Path::new("/"));
+        assert_eq!(user_path(&user, Path::new("")), Path::new("/"));
+        let user = AnttpUser {
+            username: "partner".to_string(),
+            permissions: UserPermissions::ALL,
+            archive: None,
+            home: Some(PathBuf::from("/partners/acme")),
+        };
+        assert_eq!(user_path(&user, Path::new("/")), Path::new("/partners/acme"));
+        assert_eq!(user_path(&user, Path::new("/dir/./file.txt")), Path::new("/partners/acme/dir/file.txt"));
+        assert_eq!(user_path(&user, Path::new("/../../other/file.txt")), Path::new("/partners/acme/other/file.txt"));
+        assert_eq!(user_path(&user, Path::new("/dir/../../..")), Path::new("/partners/acme"));
+    }
+
     #[tokio::test]
     async fn test_resolve_pointer_none() {
         let addr = "some_address".to_string();
         let anttp = Anttp::new(addr).unwrap();
         // Should succeed immediately as there is no pointer
-        let result: Result<()> = anttp.resolve_pointer().await;
-        result.unwrap();
+        let result: Result<String> = anttp.head().await;
+        assert_eq!(result.unwrap(), "some_address");
     }
 }
@@ -0,0 +1,216 @@
+//! Three-way merge of diverging archives: the changes made between a common base and our head are
+//! applied on top of their head. Files are compared by their listing `Item`s, so content is only
+//! fetched for files that are actually copied across.
+
+use crate::proto::archive::{File, Item};
+use crate::{Anttp, is_directory, join_path, split_path};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use unftp_core::storage::{Error, ErrorKind, Result};
+
+/// How a path changed on both sides of a merge is resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Keep whichever version was modified last. A modified file wins over a deletion.
+    NewestWins,
+    /// Keep their version and store ours next to it as `name.conflict-<timestamp>`.
+    #[default]
+    KeepBoth,
+    /// Fail the merge.
+    Reject,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newest-wins" => Ok(ConflictPolicy::NewestWins),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            "reject" => Ok(ConflictPolicy::Reject),
+            _ => Err(format!("unknown conflict policy '{}', expected newest-wins, keep-both or reject", s)),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::NewestWins => write!(f, "newest-wins"),
+            ConflictPolicy::KeepBoth => write!(f, "keep-both"),
+            ConflictPolicy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// Every file in an archive, keyed by path.
+type Snapshot = BTreeMap<String, Item>;
+
+/// A change to their head that carries over one of ours.
+#[derive(Debug, PartialEq)]
+enum Change {
+    /// Copy our file at `from` to `to`.
+    Copy { from: String, to: String },
+    Remove(String),
+}
+
+impl Anttp {
+    /// Merges the changes made between `base` and `ours` into `theirs`, resolving paths changed
+    /// on both sides with the configured conflict policy. Returns the merged archive address.
+    pub async fn merge(&self, base: &str, ours: &str, theirs: &str) -> Result<String> {
+        let changes = plan(
+            &self.snapshot(base).await?,
+            &self.snapshot(ours).await?,
+            &self.snapshot(theirs).await?,
+            self.config.conflict_policy,
+        )?;
+        let mut address = theirs.to_string();
+        for change in changes {
+            address = match change {
+                Change::Copy { from, to } => {
+                    let content = self.get_archive_at(ours.to_string(), from).await?.content.unwrap_or_default();
+                    let (dir, name) = split_path(Path::new(&to));
+                    self.update_archive_at(address, dir, vec![File { name, content: content.into() }]).await?
+                }
+                Change::Remove(path) => self.truncate_archive_at(address, path).await?,
+            };
+        }
+        Ok(address)
+    }
+
+    async fn snapshot(&self, address: &str) -> Result<Snapshot> {
+        let mut files = Snapshot::new();
+        let mut pending = vec!["/".to_string()];
+        while let Some(dir) = pending.pop() {
+            let listing = self.get_archive_at(address.to_string(), dir.clone()).await?;
+            for item in listing.items {
+                let path = join_path(&dir, &item.name);
+                if is_directory(&item) {
+                    pending.push(path);
+                } else {
+                    files.insert(path, item);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Works out which of our changes to apply to their head.
+fn plan(base: &Snapshot, ours: &Snapshot, theirs: &Snapshot, policy: ConflictPolicy) -> Result<Vec<Change>> {
+    let paths: BTreeSet<&String> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+    let mut changes = Vec::new();
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        if same(o, b) || same(o, t) {
+            // Unchanged by us, or changed the same way by both
+            continue;
+        }
+        if same(t, b) {
+            changes.push(match o {
+                Some(_) => Change::Copy { from: path.clone(), to: path.clone() },
+                None => Change::Remove(path.clone()),
+            });
+            continue;
+        }
+        // Changed differently on both sides
+        match (policy, o, t) {
+            (ConflictPolicy::Reject, _, _) => {
+                return Err(Error::new(ErrorKind::TransientFileNotAvailable, format!("conflicting changes to {}", path)));
+            }
+            // Deleted by us but modified by them: keep theirs
+            (_, None, _) => {}
+            // Modified by us but deleted by them: restore ours
+            (_, Some(_), None) => changes.push(Change::Copy { from: path.clone(), to: path.clone() }),
+            (ConflictPolicy::NewestWins, Some(o), Some(t)) => {
+                if o.modified > t.modified {
+                    changes.push(Change::Copy { from: path.clone(), to: path.clone() });
+                }
+            }
+            (ConflictPolicy::KeepBoth, Some(o), Some(_)) => {
+                changes.push(Change::Copy { from: path.clone(), to: format!("{}.conflict-{}", path, o.modified) });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Listings carry no content hash, so files are considered the same when size and modification
+/// time match.
+fn same(a: Option<&Item>, b: Option<&Item>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.size == b.size && a.modified == b.modified,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(files: &[(&str, u64, u64)]) -> Snapshot {
+        files.iter()
+            .map(|&(path, size, modified)| {
+                (path.to_string(), Item {
+                    name: path.rsplit('/').next().unwrap().to_string(),
+                    size,
+                    modified,
+                    r#type: "file".to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn copy(from: &str, to: &str) -> Change {
+        Change::Copy { from: from.to_string(), to: to.to_string() }
+    }
+
+    #[test]
+    fn test_non_overlapping_changes() {
+        let base = snapshot(&[("/a", 1, 1), ("/b", 1, 1), ("/c", 1, 1)]);
+        let ours = snapshot(&[("/a", 2, 2), ("/c", 1, 1), ("/new", 1, 2)]);
+        let theirs = snapshot(&[("/a", 1, 1), ("/b", 1, 1), ("/theirs", 1, 3)]);
+        let changes = plan(&base, &ours, &theirs, ConflictPolicy::Reject).unwrap();
+        assert_eq!(changes, vec![copy("/a", "/a"), Change::Remove("/b".to_string()), copy("/new", "/new")]);
+    }
+
+    #[test]
+    fn test_identical_changes_do_not_conflict() {
+        let base = snapshot(&[("/a", 1, 1)]);
+        let ours = snapshot(&[("/b", 2, 2)]);
+        let theirs = snapshot(&[("/b", 2, 2)]);
+        assert!(plan(&base, &ours, &theirs, ConflictPolicy::Reject).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let base = snapshot(&[("/a", 1, 1)]);
+        let ours = snapshot(&[("/a", 2, 3)]);
+        let theirs = snapshot(&[("/a", 3, 2)]);
+        assert_eq!(plan(&base, &ours, &theirs, ConflictPolicy::NewestWins).unwrap(), vec![copy("/a", "/a")]);
+        assert!(plan(&base, &theirs, &ours, ConflictPolicy::NewestWins).unwrap().is_empty());
+        assert_eq!(plan(&base, &ours, &theirs, ConflictPolicy::KeepBoth).unwrap(), vec![copy("/a", "/a.conflict-3")]);
+        let error = plan(&base, &ours, &theirs, ConflictPolicy::Reject).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TransientFileNotAvailable);
+    }
+
+    #[test]
+    fn test_modification_wins_over_deletion() {
+        let base = snapshot(&[("/a", 1, 1)]);
+        let modified = snapshot(&[("/a", 2, 2)]);
+        let deleted = snapshot(&[]);
+        assert_eq!(plan(&base, &modified, &deleted, ConflictPolicy::KeepBoth).unwrap(), vec![copy("/a", "/a")]);
+        assert!(plan(&base, &deleted, &modified, ConflictPolicy::NewestWins).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_conflict_policy_from_str() {
+        for policy in [ConflictPolicy::NewestWins, ConflictPolicy::KeepBoth, ConflictPolicy::Reject] {
+            assert_eq!(policy.to_string().parse::<ConflictPolicy>().unwrap(), policy);
+        }
+        assert!("other".parse::<ConflictPolicy>().is_err());
+    }
+}
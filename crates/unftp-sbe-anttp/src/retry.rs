@@ -0,0 +1,116 @@
+//! Deadlines and retries for AntTP calls. Calls that fail because AntTP was briefly unreachable
+//! or slow are retried using exponential backoff with jitter, so that sessions don't all retry in
+//! lockstep.
+
+use crate::config::AnttpConfig;
+use log::debug;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Runs `call` until it succeeds, fails with a status that is not worth retrying, or the configured
+/// number of retries is used up. Only use this for calls that are safe to repeat.
+pub(crate) async fn with_retry<T, F, Fut>(config: &AnttpConfig, mut call: F) -> Result<T, tonic::Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, tonic::Status>>,
+{
+    let mut backoff = config.retry_initial_backoff;
+    let mut retries = 0;
+    loop {
+        match call().await {
+            Err(status) if retries < config.retry_attempts && is_retryable(&status) => {
+                retries += 1;
+                let delay = jitter(backoff);
+                debug!("AntTP call failed ({}), retry {} of {} in {:?}", status.message(), retries, config.retry_attempts, delay);
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(config.retry_max_backoff);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Fails `call` with `DeadlineExceeded` if it takes longer than `timeout`.
+pub(crate) async fn with_deadline<T>(timeout: Duration, call: impl Future<Output = Result<T, tonic::Status>>) -> Result<T, tonic::Status> {
+    match tokio::time::timeout(timeout, call).await {
+        Ok(result) => result,
+        Err(_) => Err(tonic::Status::deadline_exceeded(format!("AntTP did not answer within {:?}", timeout))),
+    }
+}
+
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(status.code(), tonic::Code::Unavailable | tonic::Code::DeadlineExceeded)
+}
+
+/// Picks a delay between half and all of `backoff`.
+fn jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let half = backoff / 2;
+    half + Duration::from_nanos(random % (half.as_nanos() as u64 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn config(retry_attempts: u32) -> AnttpConfig {
+        AnttpConfig {
+            retry_attempts,
+            retry_initial_backoff: Duration::from_millis(1),
+            retry_max_backoff: Duration::from_millis(2),
+            ..Default::default()
+        }
+    }
+
+    /// Fails with `code` for the first `failures` calls.
+    async fn flaky(calls: &AtomicU32, failures: u32, code: tonic::Code) -> Result<u32, tonic::Status> {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+        if call < failures { Err(tonic::Status::new(code, "flaky")) } else { Ok(call) }
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        let calls = AtomicU32::new(0);
+        let result = with_retry(&config(3), || flaky(&calls, 2, tonic::Code::Unavailable)).await;
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_limited() {
+        let calls = AtomicU32::new(0);
+        let result = with_retry(&config(2), || flaky(&calls, 5, tonic::Code::DeadlineExceeded)).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failures_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let result = with_retry(&config(3), || flaky(&calls, 1, tonic::Code::NotFound)).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let result = with_deadline(Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }).await;
+        assert_eq!(result.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+        let result = with_deadline(Duration::from_secs(5), async { Ok(1) }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_jitter_range() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let delay = jitter(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Upload staging: content is buffered in memory up to the configured limit and spooled to a
+//! local staging directory beyond that, so that slow or large uploads don't pin memory while
+//! they are being received.
+
+use crate::config::AnttpConfig;
+use cap_std::ambient_authority;
+use cap_std::fs::Dir;
+use prost::bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use unftp_core::storage::{Error, ErrorKind, Result};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+static NEXT_SPOOL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Content received from an FTP client.
+pub(crate) enum Upload {
+    Memory(Bytes),
+    Spooled { file: SpoolFile, len: u64 },
+}
+
+/// A file in the staging directory, removed when dropped.
+pub(crate) struct SpoolFile {
+    dir: Dir,
+    name: String,
+}
+
+impl SpoolFile {
+    fn create(config: &AnttpConfig) -> Result<(SpoolFile, tokio::fs::File)> {
+        Dir::create_ambient_dir_all(&config.staging_dir, ambient_authority()).map_err(local_error)?;
+        let dir = Dir::open_ambient_dir(&config.staging_dir, ambient_authority()).map_err(local_error)?;
+        let name = format!("upload-{}-{}", std::process::id(), NEXT_SPOOL_ID.fetch_add(1, Ordering::Relaxed));
+        let file = dir.create(&name).map_err(local_error)?;
+        Ok((SpoolFile { dir, name }, tokio::fs::File::from_std(file.into_std())))
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.dir.remove_file(&self.name) {
+            log::warn!("Failed to remove staged upload {}: {}", self.name, e);
+        }
+    }
+}
+
+impl Upload {
+    /// Reads `reader` to the end, spooling to disk once `upload_memory_limit` is exceeded. Fails as
+    /// soon as more than `max_upload_size` or `message_limit` bytes have arrived, the latter being
+    /// what is left of the gRPC message size for this upload.
+    pub(crate) async fn receive<R: AsyncRead + Unpin>(mut reader: R, config: &AnttpConfig, message_limit: u64) -> Result<Upload> {
+        let mut buffer = Vec::new();
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut spool: Option<(SpoolFile, tokio::fs::File)> = None;
+        let mut len = 0u64;
+        loop {
+            let n = reader.read(&mut chunk).await.map_err(local_error)?;
+            if n == 0 {
+                break;
+            }
+            len += n as u64;
+            if config.max_upload_size.is_some_and(|max| len > max) {
+                return Err(Error::new(ErrorKind::ExceededStorageAllocationError, "upload exceeds the maximum upload size"));
+            }
+            if len > message_limit {
+                return Err(Error::new(ErrorKind::ExceededStorageAllocationError, "file exceeds the configured gRPC message size limit"));
+            }
+            match spool.as_mut() {
+                Some((_, file)) => file.write_all(&chunk[..n]).await.map_err(local_error)?,
+                None => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    if buffer.len() > config.upload_memory_limit {
+                        let (spool_file, mut file) = SpoolFile::create(config)?;
+                        file.write_all(&buffer).await.map_err(local_error)?;
+                        buffer = Vec::new();
+                        spool = Some((spool_file, file));
+                    }
+                }
+            }
+        }
+
+        match spool {
+            Some((file, mut handle)) => {
+                handle.flush().await.map_err(local_error)?;
+                Ok(Upload::Spooled { file, len })
+            }
+            None => Ok(Upload::Memory(buffer.into())),
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Upload::Memory(content) => content.len() as u64,
+            Upload::Spooled { len, .. } => *len,
+        }
+    }
+
+    /// Loads the upload for sending. AntTP's `UpdateArchive` is a unary call, so spooled content is
+    /// only materialised for the duration of that request, while buffered content is shared rather
+    /// than copied. The upload is kept, so it can be sent again if the write has to be re-applied.
+    pub(crate) async fn to_bytes(&self) -> Result<Bytes> {
+        match self {
+            Upload::Memory(content) => Ok(content.clone()),
+            Upload::Spooled { file, len } => {
+                let handle = file.dir.open(&file.name).map_err(local_error)?;
+                let mut handle = tokio::fs::File::from_std(handle.into_std());
+                let mut content = Vec::with_capacity(*len as usize);
+                handle.read_to_end(&mut content).await.map_err(local_error)?;
+                Ok(content.into())
+            }
+        }
+    }
+}
+
+fn local_error(e: std::io::Error) -> Error {
+    Error::new(ErrorKind::LocalError, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(upload_memory_limit: usize, max_upload_size: Option<u64>) -> AnttpConfig {
+        AnttpConfig {
+            upload_memory_limit,
+            staging_dir: std::env::temp_dir().join("antftp-staging-test"),
+            max_upload_size,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_small_upload_stays_in_memory() {
+        let upload = Upload::receive(b"hello".as_slice(), &config(16, None), u64::MAX).await.unwrap();
+        assert!(matches!(upload, Upload::Memory(_)));
+        assert_eq!(upload.len(), 5);
+        assert_eq!(upload.to_bytes().await.unwrap(), b"hello".as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_large_upload_is_spooled_and_cleaned_up() {
+        let content = vec![7u8; 200 * 1024];
+        let upload = Upload::receive(content.as_slice(), &config(1024, None), u64::MAX).await.unwrap();
+        let path = match &upload {
+            Upload::Spooled { file, .. } => std::env::temp_dir().join("antftp-staging-test").join(&file.name),
+            Upload::Memory(_) => panic!("expected upload to be spooled"),
+        };
+        assert!(path.exists());
+        assert_eq!(upload.len(), content.len() as u64);
+        assert_eq!(upload.to_bytes().await.unwrap(), content);
+        drop(upload);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_max_size_rejected() {
+        let result = Upload::receive(vec![0u8; 100].as_slice(), &config(16, Some(50)), u64::MAX).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ExceededStorageAllocationError);
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_message_limit_rejected() {
+        let result = Upload::receive(vec![0u8; 100].as_slice(), &config(16, None), 50).await;
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ExceededStorageAllocationError);
+        let upload = Upload::receive(vec![0u8; 50].as_slice(), &config(16, None), 50).await.unwrap();
+        assert_eq!(upload.len(), 50);
+    }
+}
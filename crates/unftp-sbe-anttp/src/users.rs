@@ -0,0 +1,385 @@
+//! Password authentication against a local JSON user file. Passwords are stored as PBKDF2-HMAC-SHA256
+//! hashes, in the same format as unFTP's JSON file authenticator:
+//!
+//! ```json
+//! [
+//!   {
+//!     "username": "alice",
+//!     "pbkdf2_salt": "<base64 salt>",
+//!     "pbkdf2_key": "<base64 derived key>",
+//!     "pbkdf2_iter": 500000,
+//!     "permissions": ["read", "upload"]
+//!   }
+//! ]
+//! ```
+//!
+//! Users without a `permissions` list may do everything.
+//!
+//! A user can also be served their own archive instead of the server's, by giving its address
+//! (`"archive"`) or the name of a pointer to it (`"pointer"`), optionally along with the pointer's
+//! `"data_key"`.
+//!
+//! With a `"home"` directory, e.g. `"/partners/acme"`, the user only sees that part of the archive.
+//! It is created the first time the user needs it.
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::pbkdf2::{PBKDF2_HMAC_SHA256, verify};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use unftp_core::auth::{AuthenticationError, Authenticator, Credentials, DefaultUser, Principal, UserDetail, UserDetailError, UserDetailProvider};
+
+/// What a user may do with the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserPermissions {
+    /// List directories and download files.
+    pub read: bool,
+    /// Upload new files.
+    pub upload: bool,
+    /// Replace or append to existing files.
+    pub overwrite: bool,
+    /// Delete files and directories.
+    pub delete: bool,
+    /// Create directories.
+    pub mkdir: bool,
+}
+
+impl UserPermissions {
+    pub const ALL: UserPermissions = UserPermissions { read: true, upload: true, overwrite: true, delete: true, mkdir: true };
+    pub const NONE: UserPermissions = UserPermissions { read: false, upload: false, overwrite: false, delete: false, mkdir: false };
+}
+
+impl Default for UserPermissions {
+    fn default() -> Self {
+        UserPermissions::ALL
+    }
+}
+
+/// A single permission as listed in the user file.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Permission {
+    Read,
+    Upload,
+    Overwrite,
+    Delete,
+    Mkdir,
+}
+
+impl FromIterator<Permission> for UserPermissions {
+    fn from_iter<I: IntoIterator<Item = Permission>>(permissions: I) -> Self {
+        permissions.into_iter().fold(UserPermissions::NONE, |mut granted, permission| {
+            match permission {
+                Permission::Read => granted.read = true,
+                Permission::Upload => granted.upload = true,
+                Permission::Overwrite => granted.overwrite = true,
+                Permission::Delete => granted.delete = true,
+                Permission::Mkdir => granted.mkdir = true,
+            }
+            granted
+        })
+    }
+}
+
+/// The archive a user is served instead of the server's.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Deserialize)]
+pub struct UserArchive {
+    /// Address of the archive. With a pointer, only used until the pointer has been resolved.
+    pub archive: Option<String>,
+    /// Name of the pointer the archive address is resolved from.
+    pub pointer: Option<String>,
+    /// Key of the pointer, when it isn't the one AntTP derives by default.
+    pub data_key: Option<String>,
+}
+
+/// Users whose permissions and archive the storage back-end takes into account.
+pub trait AnttpUserDetail: UserDetail {
+    fn permissions(&self) -> UserPermissions {
+        UserPermissions::ALL
+    }
+
+    /// The user's own archive, or `None` to serve the server's.
+    fn archive(&self) -> Option<&UserArchive> {
+        None
+    }
+}
+
+/// Anonymous users may do everything.
+impl AnttpUserDetail for DefaultUser {}
+
+/// A user authenticated against a [`UserFile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnttpUser {
+    pub username: String,
+    pub permissions: UserPermissions,
+    pub archive: Option<UserArchive>,
+    /// Directory of the archive the user is confined to.
+    pub home: Option<PathBuf>,
+}
+
+impl UserDetail for AnttpUser {
+    fn home(&self) -> Option<&Path> {
+        self.home.as_deref()
+    }
+}
+
+impl AnttpUserDetail for AnttpUser {
+    fn permissions(&self) -> UserPermissions {
+        self.permissions
+    }
+
+    fn archive(&self) -> Option<&UserArchive> {
+        self.archive.as_ref()
+    }
+}
+
+impl fmt::Display for AnttpUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.username)
+    }
+}
+
+/// An entry of the user file, as written on disk.
+#[derive(Deserialize)]
+struct UserEntry {
+    username: String,
+    pbkdf2_salt: String,
+    pbkdf2_key: String,
+    pbkdf2_iter: NonZeroU32,
+    permissions: Option<Vec<Permission>>,
+    #[serde(flatten)]
+    archive: UserArchive,
+    home: Option<PathBuf>,
+}
+
+struct PasswordHash {
+    salt: Vec<u8>,
+    key: Vec<u8>,
+    iterations: NonZeroU32,
+}
+
+struct UserRecord {
+    hash: Arc<PasswordHash>,
+    permissions: UserPermissions,
+    archive: Option<UserArchive>,
+    home: Option<PathBuf>,
+}
+
+/// Users that may log in, loaded from a JSON file. Acts both as the authenticator and as the
+/// provider of the [`AnttpUser`] handed to the storage back-end.
+pub struct UserFile {
+    users: HashMap<String, UserRecord>,
+    /// Checked against for unknown users, so they take as long to reject as wrong passwords.
+    dummy: Arc<PasswordHash>,
+}
+
+impl UserFile {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let json = std::fs::read_to_string(path)?;
+        Self::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let entries: Vec<UserEntry> = serde_json::from_str(json)?;
+        let mut users = HashMap::new();
+        for entry in entries {
+            let salt = STANDARD.decode(&entry.pbkdf2_salt)
+                .map_err(|e| format!("invalid salt for user '{}': {}", entry.username, e))?;
+            let key = STANDARD.decode(&entry.pbkdf2_key)
+                .map_err(|e| format!("invalid key for user '{}': {}", entry.username, e))?;
+            // `verify` only compares as many bytes as the stored key has, so a short key would be
+            // easy to guess
+            if key.len() != SHA256_OUTPUT_LEN {
+                return Err(format!("key of user '{}' must be {} bytes long", entry.username, SHA256_OUTPUT_LEN).into());
+            }
+            if salt.is_empty() {
+                return Err(format!("salt of user '{}' is empty", entry.username).into());
+            }
+            let archive = match entry.archive {
+                UserArchive { archive: None, pointer: None, data_key: None } => None,
+                UserArchive { archive: None, pointer: None, .. } => {
+                    return Err(format!("user '{}' has a data_key but no pointer", entry.username).into());
+                }
+                archive => Some(archive),
+            };
+            let home = match entry.home {
+                Some(home) if !is_home_directory(&home) => {
+                    return Err(format!("home of user '{}' must be an absolute path without '..'", entry.username).into());
+                }
+                // The archive root is no confinement at all
+                Some(home) if home.parent().is_none() => None,
+                home => home,
+            };
+            let record = UserRecord {
+                hash: Arc::new(PasswordHash { salt, key, iterations: entry.pbkdf2_iter }),
+                permissions: entry.permissions.map_or(UserPermissions::ALL, |permissions| permissions.into_iter().collect()),
+                archive,
+                home,
+            };
+            if users.insert(entry.username.clone(), record).is_some() {
+                return Err(format!("user '{}' is listed more than once", entry.username).into());
+            }
+        }
+        let iterations = users.values().map(|record| record.hash.iterations).max().unwrap_or(NonZeroU32::MIN);
+        let dummy = Arc::new(PasswordHash { salt: b"antftp-dummy".to_vec(), key: vec![0; SHA256_OUTPUT_LEN], iterations });
+        Ok(UserFile { users, dummy })
+    }
+
+    /// The archives of users that aren't served the server's.
+    pub fn archives(&self) -> impl Iterator<Item = &UserArchive> {
+        self.users.values().filter_map(|record| record.archive.as_ref())
+    }
+}
+
+fn is_home_directory(path: &Path) -> bool {
+    path.has_root() && path.components().all(|component| matches!(component, Component::RootDir | Component::Normal(_)))
+}
+
+impl fmt::Debug for UserFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the password hashes out of the logs
+        f.debug_struct("UserFile").field("users", &self.users.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+#[async_trait]
+impl Authenticator for UserFile {
+    async fn authenticate(&self, username: &str, creds: &Credentials) -> Result<Principal, AuthenticationError> {
+        let password = creds.password.clone().ok_or(AuthenticationError::BadPassword)?;
+        let record = self.users.get(username);
+        let hash = record.map_or_else(|| self.dummy.clone(), |record| record.hash.clone());
+        // PBKDF2 is slow on purpose, so keep it off the async workers
+        let verified = tokio::task::spawn_blocking(move || {
+            verify(PBKDF2_HMAC_SHA256, hash.iterations, &hash.salt, password.as_bytes(), &hash.key).is_ok()
+        })
+        .await
+        .map_err(|e| AuthenticationError::with_source("password check failed", e))?;
+        match (record, verified) {
+            (None, _) => Err(AuthenticationError::BadUser),
+            (Some(_), false) => Err(AuthenticationError::BadPassword),
+            (Some(_), true) => Ok(Principal { username: username.to_string() }),
+        }
+    }
+}
+
+#[async_trait]
+impl UserDetailProvider for UserFile {
+    type User = AnttpUser;
+
+    async fn provide_user_detail(&self, principal: &Principal) -> Result<AnttpUser, UserDetailError> {
+        let record = self.users.get(&principal.username)
+            .ok_or_else(|| UserDetailError::new(format!("unknown user '{}'", principal.username)))?;
+        Ok(AnttpUser {
+            username: principal.username.clone(),
+            permissions: record.permissions,
+            archive: record.archive.clone(),
+            home: record.home.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_file(password: &str) -> UserFile {
+        user_file_with_permissions(password, "")
+    }
+
+    /// `extra` is inserted as extra JSON fields of the user.
+    fn user_file_with_permissions(password: &str, extra: &str) -> UserFile {
+        UserFile::from_json(&user_json(password, extra)).unwrap()
+    }
+
+    fn user_json(password: &str, extra: &str) -> String {
+        let salt = b"0123456789abcdef";
+        let iterations = NonZeroU32::new(1000).unwrap();
+        let mut key = [0u8; SHA256_OUTPUT_LEN];
+        ring::pbkdf2::derive(PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut key);
+        format!(
+            r#"[{{"username": "alice", "pbkdf2_salt": "{}", "pbkdf2_key": "{}", "pbkdf2_iter": 1000{}}}]"#,
+            STANDARD.encode(salt),
+            STANDARD.encode(key),
+            extra
+        )
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let users = user_file("secret");
+        let principal = users.authenticate("alice", &"secret".into()).await.unwrap();
+        assert_eq!(principal.username, "alice");
+        assert!(matches!(users.authenticate("alice", &"wrong".into()).await, Err(AuthenticationError::BadPassword)));
+        assert!(matches!(users.authenticate("bob", &"secret".into()).await, Err(AuthenticationError::BadUser)));
+        assert_eq!(users.dummy.iterations.get(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_provide_user_detail() {
+        let users = user_file("secret");
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        assert_eq!(user.to_string(), "alice");
+        assert_eq!(user.permissions, UserPermissions::ALL);
+        assert_eq!(user.archive(), None);
+        assert_eq!(user.home(), None);
+        assert!(users.provide_user_detail(&Principal { username: "bob".to_string() }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let users = user_file_with_permissions("secret", r#", "permissions": ["read", "upload"]"#);
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        assert_eq!(user.permissions(), UserPermissions { read: true, upload: true, ..UserPermissions::NONE });
+
+        let users = user_file_with_permissions("secret", r#", "permissions": []"#);
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        assert_eq!(user.permissions(), UserPermissions::NONE);
+        assert_eq!(DefaultUser.permissions(), UserPermissions::ALL);
+    }
+
+    #[tokio::test]
+    async fn test_user_archive() {
+        let users = user_file_with_permissions("secret", r#", "pointer": "team-a", "data_key": "key""#);
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        let archive = UserArchive { archive: None, pointer: Some("team-a".to_string()), data_key: Some("key".to_string()) };
+        assert_eq!(user.archive(), Some(&archive));
+        assert_eq!(users.archives().collect::<Vec<_>>(), vec![&archive]);
+        assert!(UserFile::from_json(&user_json("secret", r#", "data_key": "key""#)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_home() {
+        let users = user_file_with_permissions("secret", r#", "home": "/partners/acme""#);
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        assert_eq!(user.home(), Some(Path::new("/partners/acme")));
+        let users = user_file_with_permissions("secret", r#", "home": "/""#);
+        let user = users.provide_user_detail(&Principal { username: "alice".to_string() }).await.unwrap();
+        assert_eq!(user.home(), None);
+        for home in ["partners", "/partners/../etc"] {
+            let json = user_json("secret", &format!(r#", "home": "{}""#, home));
+            assert!(UserFile::from_json(&json).is_err(), "{}", home);
+        }
+    }
+
+    #[test]
+    fn test_weak_hash_rejected() {
+        let short_key = r#"[{"username": "alice", "pbkdf2_salt": "c2FsdA==", "pbkdf2_key": "AAAA", "pbkdf2_iter": 1}]"#;
+        assert!(UserFile::from_json(short_key).is_err());
+        let json = user_json("secret", "").replace("MDEyMzQ1Njc4OWFiY2RlZg==", "");
+        assert!(UserFile::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn test_invalid_user_file() {
+        assert!(UserFile::from_json(&user_json("secret", r#", "permissions": ["admin"]"#)).is_err());
+        assert!(UserFile::from_json("{}").is_err());
+        assert!(UserFile::from_json(r#"[{"username": "alice", "pbkdf2_salt": "!", "pbkdf2_key": "", "pbkdf2_iter": 1}]"#).is_err());
+        assert!(UserFile::from_json(&user_json("secret", "").replace("1000", "0")).is_err());
+    }
+}
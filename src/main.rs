@@ -4,11 +4,15 @@ use unftp_sbe_anttp::proto::pointer::pointer_service_client::PointerServiceClien
 use unftp_sbe_anttp::proto::pointer::{GetPointerRequest, UpdatePointerRequest, Pointer};
 use unftp_sbe_anttp::proto::archive::archive_service_client::ArchiveServiceClient;
 use unftp_sbe_anttp::proto::archive::PushArchiveRequest;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::time::{self, Duration};
 use log::{info, error};
-use unftp_sbe_anttp::ServerExt;
+use unftp_sbe_anttp::{AnttpConfig, ConflictPolicy, ServerExt, StoreType, UserFile};
+
+const MIB: usize = 1024 * 1024;
+const GREETING: &str = "Welcome to ANT FTP server";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -25,9 +29,89 @@ struct Args {
     #[arg(short = 'l', long = "listen-address", default_value = "127.0.0.1:2121")]
     listen_address: String,
 
+    /// JSON file listing the users allowed to log in, with PBKDF2 hashed passwords. Without it, any user name and password is accepted
+    #[arg(short = 'u', long = "users-file")]
+    users_file: Option<PathBuf>,
+
+    /// Where AntTP stores written archives and pointers: memory, disk or network
+    #[arg(short = 's', long = "store-type", default_value = "disk")]
+    store_type: StoreType,
+
+    /// Comma separated stores to read archives from, in order, e.g. disk,network (defaults to the store type)
+    #[arg(long = "read-stores", value_delimiter = ',')]
+    read_stores: Vec<StoreType>,
+
+    /// Copy archives found further down the read stores into the first one
+    #[arg(long = "warm-reads")]
+    warm_reads: bool,
+
     /// Network sync interval in minutes (only used when a pointer is provided)
     #[arg(short = 'n', long = "network-sync-timer", default_value = "10")]
     network_sync_timer: u64,
+
+    /// Maximum MiB of each upload held in memory before it is spooled to the staging directory
+    #[arg(long = "upload-memory-limit", default_value = "8")]
+    upload_memory_limit: usize,
+
+    /// Directory used to spool large uploads (defaults to a directory under the system temp dir)
+    #[arg(long = "staging-dir")]
+    staging_dir: Option<PathBuf>,
+
+    /// Optional maximum upload size in MiB
+    #[arg(long = "max-upload-size")]
+    max_upload_size: Option<u64>,
+
+    /// Maximum size in MiB of a gRPC message received from AntTP (the largest downloadable file)
+    #[arg(long = "grpc-max-decode-size", default_value = "64")]
+    grpc_max_decode_size: usize,
+
+    /// Maximum size in MiB of a gRPC message sent to AntTP (the largest uploadable file)
+    #[arg(long = "grpc-max-encode-size", default_value = "64")]
+    grpc_max_encode_size: usize,
+
+    /// Compress gRPC messages exchanged with AntTP using gzip
+    #[arg(long = "grpc-gzip")]
+    grpc_gzip: bool,
+
+    /// Reject uploads, deletes, renames and directory changes, and never update the pointer
+    #[arg(long = "read-only")]
+    read_only: bool,
+
+    /// Allow RMD to remove non-empty directories along with their contents
+    #[arg(long = "recursive-rmd")]
+    recursive_rmd: bool,
+
+    /// How files changed concurrently through the same pointer are resolved: newest-wins, keep-both or reject
+    #[arg(long = "conflict-policy", default_value = "keep-both")]
+    conflict_policy: ConflictPolicy,
+
+    /// How often a failed AntTP read or archive update is retried while AntTP is unavailable
+    #[arg(long = "retry-attempts", default_value = "3")]
+    retry_attempts: u32,
+
+    /// Delay in milliseconds before the first retry, doubled for each retry after that
+    #[arg(long = "retry-backoff", default_value = "200")]
+    retry_backoff: u64,
+
+    /// Maximum delay in milliseconds between retries
+    #[arg(long = "retry-max-backoff", default_value = "5000")]
+    retry_max_backoff: u64,
+
+    /// Seconds allowed for connecting to AntTP
+    #[arg(long = "connect-timeout", default_value = "10")]
+    connect_timeout: u64,
+
+    /// Seconds allowed for each AntTP call that reads an archive or pointer
+    #[arg(long = "read-timeout", default_value = "60")]
+    read_timeout: u64,
+
+    /// Seconds allowed for each AntTP call that writes an archive or pointer
+    #[arg(long = "write-timeout", default_value = "300")]
+    write_timeout: u64,
+
+    /// Seconds allowed for pushing an archive and its pointer to the network
+    #[arg(long = "push-timeout", default_value = "1800")]
+    push_timeout: u64,
 }
 
 #[tokio::main]
@@ -35,30 +119,80 @@ pub async fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Args::parse();
 
-    // Use the pointer-aware server builder when a pointer was specified; otherwise the default
-    let server = if let Some(ref pointer_name) = args.pointer_name {
-        let endpoint = std::env::var("ANTTP_GRPC_ENDPOINT").unwrap_or_else(|_| "http://localhost:18887".to_string());
-        let channel = Channel::from_shared(endpoint.clone()).expect("Invalid endpoint").connect_lazy();
-        let pointer_client = PointerServiceClient::new(channel.clone());
+    let mut config = AnttpConfig {
+        upload_memory_limit: args.upload_memory_limit * MIB,
+        max_upload_size: args.max_upload_size.map(|size| size * MIB as u64),
+        max_decoding_message_size: args.grpc_max_decode_size * MIB,
+        max_encoding_message_size: args.grpc_max_encode_size * MIB,
+        store_type: args.store_type,
+        read_store_types: args.read_stores,
+        warm_reads: args.warm_reads,
+        compression: args.grpc_gzip,
+        read_only: args.read_only,
+        recursive_rmd: args.recursive_rmd,
+        conflict_policy: args.conflict_policy,
+        retry_attempts: args.retry_attempts,
+        retry_initial_backoff: Duration::from_millis(args.retry_backoff),
+        retry_max_backoff: Duration::from_millis(args.retry_max_backoff),
+        connect_timeout: Duration::from_secs(args.connect_timeout),
+        read_timeout: Duration::from_secs(args.read_timeout),
+        write_timeout: Duration::from_secs(args.write_timeout),
+        push_timeout: Duration::from_secs(args.push_timeout),
+        ..Default::default()
+    };
+    if let Some(staging_dir) = args.staging_dir {
+        config.staging_dir = staging_dir;
+    }
 
-        // Start background network sync job (only when pointer provided)
-        start_network_sync_job(pointer_name.clone(), args.network_sync_timer, endpoint);
+    let users = args.users_file.as_ref()
+        .map(|users_file| Arc::new(UserFile::from_file(users_file).expect("Failed to load users file")));
 
-        libunftp::Server::with_anttp_pointer(&args.archive, pointer_client, pointer_name.clone())
+    // Start a background network sync job for the server's pointer and each user's own. Writes to
+    // the network store are already on the network, so there is nothing to sync. Read-only servers
+    // never change the pointer.
+    let mut pointers: Vec<(String, Option<String>)> = args.pointer_name.iter().map(|pointer_name| (pointer_name.clone(), None)).collect();
+    if let Some(ref users) = users {
+        pointers.extend(users.archives().filter_map(|archive| Some((archive.pointer.clone()?, archive.data_key.clone()))));
+    }
+    pointers.sort();
+    pointers.dedup();
+    if args.read_only {
+        info!("Serving the archive read-only, network sync disabled");
+    } else if args.store_type == StoreType::Network {
+        info!("Writing straight to the network, network sync disabled");
     } else {
-        libunftp::Server::with_anttp(&args.archive)
+        let endpoint = std::env::var("ANTTP_GRPC_ENDPOINT").unwrap_or_else(|_| "http://localhost:18887".to_string());
+        for (pointer_name, data_key) in pointers {
+            start_network_sync_job(pointer_name, data_key, args.network_sync_timer, endpoint.clone(), config.clone());
+        }
     }
-    .greeting("Welcome to ANT FTP server")
-    .passive_ports(50000..=65535)
-    .build()
-    .unwrap();
 
-    server.listen(&args.listen_address).await.expect("Failed to start FTP listener");
+    // The pointer, when specified, is resolved by the storage back-end before every read
+    match users {
+        Some(users) => {
+            let server = libunftp::Server::with_anttp_users(&args.archive, args.pointer_name.clone(), config, users)
+                .greeting(GREETING)
+                .passive_ports(50000..=65535)
+                .build()
+                .unwrap();
+            server.listen(&args.listen_address).await.expect("Failed to start FTP listener");
+        }
+        None => {
+            let server = libunftp::Server::with_anttp_config(&args.archive, args.pointer_name.clone(), config)
+                .greeting(GREETING)
+                .passive_ports(50000..=65535)
+                .build()
+                .unwrap();
+            server.listen(&args.listen_address).await.expect("Failed to start FTP listener");
+        }
+    }
 }
 
-fn start_network_sync_job(pointer_name: String, sync_minutes: u64, endpoint: String) {
+fn start_network_sync_job(pointer_name: String, data_key: Option<String>, sync_minutes: u64, endpoint: String, config: AnttpConfig) {
     tokio::spawn(async move {
-        let channel_bg = Channel::from_shared(endpoint).expect("Invalid endpoint").connect_lazy();
+        let channel_bg = Channel::from_shared(endpoint).expect("Invalid endpoint")
+            .connect_timeout(config.connect_timeout)
+            .connect_lazy();
         let mut pointer_client_bg = PointerServiceClient::new(channel_bg.clone());
         let mut archive_client_bg = ArchiveServiceClient::new(channel_bg);
         let last_synced: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
@@ -67,8 +201,8 @@ fn start_network_sync_job(pointer_name: String, sync_minutes: u64, endpoint: Str
         loop {
             interval.tick().await;
             // Read current archive address from disk (via pointer)
-            let req = tonic::Request::new(GetPointerRequest { address: pointer_name.clone(), data_key: None });
-            match pointer_client_bg.get_pointer(req).await {
+            let req = tonic::Request::new(GetPointerRequest { address: pointer_name.clone(), data_key: data_key.clone() });
+            match time::timeout(config.read_timeout, pointer_client_bg.get_pointer(req)).await.map_err(timed_out).flatten() {
                 Ok(resp) => {
                     if let Some(ptr) = resp.into_inner().pointer {
                         let current_addr = ptr.content;
@@ -76,8 +210,8 @@ fn start_network_sync_job(pointer_name: String, sync_minutes: u64, endpoint: Str
                         if last.as_ref() != Some(&current_addr) {
                             info!("Network sync: Change detected. Pushing archive {} to network", current_addr);
                             // Push archive to network
-                            let push_req = tonic::Request::new(PushArchiveRequest { address: current_addr.clone(), store_type: Some("network".to_string()) });
-                            if let Err(e) = archive_client_bg.push_archive(push_req).await {
+                            let push_req = tonic::Request::new(PushArchiveRequest { address: current_addr.clone(), store_type: Some(StoreType::Network.to_string()) });
+                            if let Err(e) = time::timeout(config.push_timeout, archive_client_bg.push_archive(push_req)).await.map_err(timed_out).flatten() {
                                 error!("Network sync: failed to push archive: {}", e);
                                 continue;
                             }
@@ -85,10 +219,10 @@ fn start_network_sync_job(pointer_name: String, sync_minutes: u64, endpoint: Str
                             let up_req = tonic::Request::new(UpdatePointerRequest {
                                 address: pointer_name.clone(),
                                 pointer: Some(Pointer { name: Some(pointer_name.clone()), content: current_addr.clone(), address: None, counter: None, cost: None }),
-                                store_type: Some("network".to_string()),
-                                data_key: None,
+                                store_type: Some(StoreType::Network.to_string()),
+                                data_key: data_key.clone(),
                             });
-                            if let Err(e) = pointer_client_bg.update_pointer(up_req).await {
+                            if let Err(e) = time::timeout(config.push_timeout, pointer_client_bg.update_pointer(up_req)).await.map_err(timed_out).flatten() {
                                 error!("Network sync: failed to update pointer on network: {}", e);
                                 continue;
                             }
@@ -104,3 +238,8 @@ fn start_network_sync_job(pointer_name: String, sync_minutes: u64, endpoint: Str
         }
     });
 }
+
+/// The error for a sync call that ran out of time.
+fn timed_out(elapsed: time::error::Elapsed) -> tonic::Status {
+    tonic::Status::deadline_exceeded(format!("AntTP did not answer in time: {}", elapsed))
+}
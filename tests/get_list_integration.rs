@@ -1,17 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
 use std::net::{TcpListener};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::TcpListenerStream;
 use suppaftp::AsyncFtpStream;
-use unftp_sbe_anttp::ServerExt;
+use unftp_sbe_anttp::{AnttpConfig, ConflictPolicy, ServerExt, StoreType, UserFile};
 use serial_test::serial;
 
 // Generated from proto (provided by unftp-sbe-anttp crate)
 use unftp_sbe_anttp::proto::archive::archive_service_server::{ArchiveService, ArchiveServiceServer};
 use unftp_sbe_anttp::proto::archive::{GetArchiveRequest, ArchiveResponse, Item};
+use unftp_sbe_anttp::proto::pointer::pointer_service_server::{PointerService, PointerServiceServer};
+use unftp_sbe_anttp::proto::pointer::{CreatePointerRequest, GetPointerRequest, Pointer, PointerResponse, UpdatePointerRequest};
 use tonic::{Request, Response, Status};
 
-struct MockArchiveService;
+const INITIAL_ADDRESS: &str = "cec7a9eb2c644b9a5de58bbcdf2e893db9f0b2acd7fc563fc849e19d1f6bd872";
+const MODIFIED: u64 = 1_700_000_000;
+const POINTER_NAME: &str = "pointer";
+
+/// Every user's password is "secret". The bot may only upload new files, and the team is served
+/// the archive behind the mock pointer. The partner only sees their home directory.
+const USERS: &str = r#"[{
+    "username": "alice",
+    "pbkdf2_salt": "YW50ZnRwLXRlc3Qtc2FsdA==",
+    "pbkdf2_key": "8fckw1DdK9NP5ZDQ/M6HwwP8K9j2z3xpb8MgaRl+uy4=",
+    "pbkdf2_iter": 1000
+}, {
+    "username": "bot",
+    "pbkdf2_salt": "YW50ZnRwLXRlc3Qtc2FsdA==",
+    "pbkdf2_key": "8fckw1DdK9NP5ZDQ/M6HwwP8K9j2z3xpb8MgaRl+uy4=",
+    "pbkdf2_iter": 1000,
+    "permissions": ["upload"]
+}, {
+    "username": "appender",
+    "pbkdf2_salt": "YW50ZnRwLXRlc3Qtc2FsdA==",
+    "pbkdf2_key": "8fckw1DdK9NP5ZDQ/M6HwwP8K9j2z3xpb8MgaRl+uy4=",
+    "pbkdf2_iter": 1000,
+    "permissions": ["upload", "overwrite"]
+}, {
+    "username": "team",
+    "pbkdf2_salt": "YW50ZnRwLXRlc3Qtc2FsdA==",
+    "pbkdf2_key": "8fckw1DdK9NP5ZDQ/M6HwwP8K9j2z3xpb8MgaRl+uy4=",
+    "pbkdf2_iter": 1000,
+    "pointer": "pointer",
+    "data_key": "team-key"
+}, {
+    "username": "partner",
+    "pbkdf2_salt": "YW50ZnRwLXRlc3Qtc2FsdA==",
+    "pbkdf2_key": "8fckw1DdK9NP5ZDQ/M6HwwP8K9j2z3xpb8MgaRl+uy4=",
+    "pbkdf2_iter": 1000,
+    "home": "/partners/acme"
+}]"#;
+
+/// Number of GetArchive calls that returned file content
+static CONTENT_READS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of upcoming GetArchive calls that fail as if AntTP were restarting
+static UNAVAILABLE_READS: AtomicU64 = AtomicU64::new(0);
+
+/// Store types requested by archive updates and truncations
+static WRITE_STORE_TYPES: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+/// Archives that only the network store has, until they are pushed to another store
+static NETWORK_ONLY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Data keys sent along with pointer reads and updates
+static POINTER_DATA_KEYS: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+/// Number of upcoming GetArchive calls that hang for a second before answering
+static SLOW_READS: AtomicU64 = AtomicU64::new(0);
+
+/// In-memory archive store keyed by address. Like AntTP, every change produces a new address.
+struct MockArchiveService {
+    archives: Mutex<HashMap<String, BTreeMap<String, Vec<u8>>>>,
+    revision: AtomicU64,
+}
+
+impl MockArchiveService {
+    fn new() -> Self {
+        let mut files = BTreeMap::new();
+        files.insert("file1.txt".to_string(), b"hello world".to_vec());
+        files.insert("dir/.metadata".to_string(), b"pad".to_vec());
+        let mut archives = HashMap::new();
+        archives.insert(INITIAL_ADDRESS.to_string(), files);
+        MockArchiveService {
+            archives: Mutex::new(archives),
+            revision: AtomicU64::new(0),
+        }
+    }
+
+    fn archive(&self, address: &str) -> Option<BTreeMap<String, Vec<u8>>> {
+        self.archives.lock().unwrap().get(address).cloned()
+    }
+
+    fn store(&self, files: BTreeMap<String, Vec<u8>>) -> String {
+        let address = format!("archive_{}", self.revision.fetch_add(1, Ordering::SeqCst) + 1);
+        self.archives.lock().unwrap().insert(address.clone(), files);
+        address
+    }
+}
+
+fn normalise(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path == "." { String::new() } else { path.to_string() }
+}
+
+fn list_dir(files: &BTreeMap<String, Vec<u8>>, dir: &str) -> Option<Vec<Item>> {
+    let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+    let mut items: BTreeMap<String, Item> = BTreeMap::new();
+    for (path, content) in files {
+        let Some(rest) = path.strip_prefix(&prefix) else { continue };
+        let item = match rest.split_once('/') {
+            Some((name, _)) => Item { name: name.to_string(), size: 0, modified: MODIFIED, r#type: "directory".to_string() },
+            None => Item { name: rest.to_string(), size: content.len() as u64, modified: MODIFIED, r#type: "file".to_string() },
+        };
+        items.insert(item.name.clone(), item);
+    }
+    if items.is_empty() && !dir.is_empty() {
+        return None;
+    }
+    Some(items.into_values().collect())
+}
+
+fn response(address: Option<String>, items: Vec<Item>, content: Option<Vec<u8>>) -> Response<ArchiveResponse> {
+    Response::new(ArchiveResponse { address, items, content })
+}
 
 #[tonic::async_trait]
 impl ArchiveService for MockArchiveService {
@@ -28,11 +143,10 @@ impl ArchiveService for MockArchiveService {
     ) -> Result<Response<unftp_sbe_anttp::proto::archive::ArchiveResponse>, Status> {
         // Mock simply echoes back the same address as confirmation
         let req = request.into_inner();
-        Ok(Response::new(unftp_sbe_anttp::proto::archive::ArchiveResponse {
-            address: Some(req.address),
-            items: vec![],
-            content: None,
-        }))
+        if req.store_type.as_deref() != Some("network") {
+            NETWORK_ONLY.lock().unwrap().retain(|address| address != &req.address);
+        }
+        Ok(response(Some(req.address), vec![], None))
     }
 
     async fn update_archive(
@@ -54,13 +168,13 @@ impl ArchiveService for MockArchiveService {
             }
         }
 
-        let mut new_address = req.address;
-        new_address.push_str("_updated");
-        Ok(Response::new(unftp_sbe_anttp::proto::archive::ArchiveResponse {
-            address: Some(new_address),
-            items: vec![],
-            content: None,
-        }))
+        WRITE_STORE_TYPES.lock().unwrap().push(req.store_type.clone());
+        let mut files = self.archive(&req.address).ok_or_else(|| Status::not_found("Archive not found"))?;
+        let dir = normalise(path);
+        for file in req.files {
+            files.insert(normalise(&format!("{}/{}", dir, file.name)), file.content.to_vec());
+        }
+        Ok(response(Some(self.store(files)), vec![], None))
     }
 
     async fn truncate_archive(
@@ -68,70 +182,139 @@ impl ArchiveService for MockArchiveService {
         request: Request<unftp_sbe_anttp::proto::archive::TruncateArchiveRequest>,
     ) -> Result<Response<unftp_sbe_anttp::proto::archive::ArchiveResponse>, Status> {
         let req = request.into_inner();
-        let mut new_address = req.address;
-        new_address.push_str("_truncated");
-        Ok(Response::new(unftp_sbe_anttp::proto::archive::ArchiveResponse {
-            address: Some(new_address),
-            items: vec![],
-            content: None,
-        }))
+        WRITE_STORE_TYPES.lock().unwrap().push(req.store_type.clone());
+        let mut files = self.archive(&req.address).ok_or_else(|| Status::not_found("Archive not found"))?;
+        let path = normalise(&req.path);
+        let prefix = format!("{}/", path);
+        let before = files.len();
+        files.retain(|name, _| name != &path && !name.starts_with(&prefix));
+        if files.len() == before {
+            return Err(Status::not_found("File not found"));
+        }
+        Ok(response(Some(self.store(files)), vec![], None))
     }
 
     async fn get_archive(
         &self,
         request: Request<GetArchiveRequest>,
     ) -> Result<Response<ArchiveResponse>, Status> {
+        if UNAVAILABLE_READS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+            return Err(Status::unavailable("restarting"));
+        }
+        if SLOW_READS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
         let req = request.into_inner();
-        let path = req.path.unwrap_or_default();
-        // Root listing
-        if path.is_empty() || path == "/" || path == "." {
-            Ok(Response::new(ArchiveResponse {
-                address: Some(req.address.clone()),
-                items: vec![
-                    Item {
-                        name: "file1.txt".to_string(),
-                        size: 11,
-                        modified: 0,
-                        r#type: "file".to_string(),
-                    },
-                    Item {
-                        name: "dir".to_string(),
-                        size: 0,
-                        modified: 0,
-                        r#type: "directory".to_string(),
-                    },
-                ],
-                content: None,
-            }))
-        } else if path == "/file1.txt" || path == "file1.txt" {
-            Ok(Response::new(ArchiveResponse {
-                address: Some(req.address.clone()),
-                items: vec![],
-                content: Some(b"hello world".to_vec()),
-            }))
+        if req.store_type.as_deref() != Some("network") && NETWORK_ONLY.lock().unwrap().contains(&req.address) {
+            return Err(Status::not_found("Archive not found"));
+        }
+        let files = self.archive(&req.address).ok_or_else(|| Status::not_found("Archive not found"))?;
+        let path = normalise(&req.path.unwrap_or_default());
+        if let Some(content) = files.get(&path) {
+            CONTENT_READS.fetch_add(1, Ordering::SeqCst);
+            Ok(response(Some(req.address), vec![], Some(content.clone())))
+        } else if let Some(items) = list_dir(&files, &path) {
+            Ok(response(Some(req.address), items, None))
         } else {
             // Unknown path
-            return Err(Status::not_found("File not found"));
+            Err(Status::not_found("File not found"))
+        }
+    }
+}
+
+/// Pointer store that only accepts an update whose counter follows on from the current one, a
+/// missing counter counting as 0.
+struct MockPointerService {
+    archives: Arc<MockArchiveService>,
+    pointer: Mutex<Pointer>,
+    /// A file another writer adds to the archive just before our next pointer update
+    interloper: Mutex<Option<(String, Vec<u8>)>>,
+}
+
+impl MockPointerService {
+    fn new(archives: Arc<MockArchiveService>) -> Self {
+        MockPointerService {
+            archives,
+            pointer: Mutex::new(Pointer {
+                name: Some(POINTER_NAME.to_string()),
+                content: INITIAL_ADDRESS.to_string(),
+                address: None,
+                counter: Some(0),
+                cost: None,
+            }),
+            interloper: Mutex::new(None),
+        }
+    }
+
+    /// Adds a file on top of the pointer's archive, as another writer would.
+    fn write_elsewhere(&self, name: &str, content: &[u8]) {
+        let mut pointer = self.pointer.lock().unwrap();
+        let mut files = self.archives.archive(&pointer.content).expect("pointer archive");
+        files.insert(name.to_string(), content.to_vec());
+        pointer.content = self.archives.store(files);
+        pointer.counter = Some(pointer.counter.unwrap_or(0) + 1);
+    }
+
+    fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        let pointer = self.pointer.lock().unwrap();
+        self.archives.archive(&pointer.content).expect("pointer archive")
+    }
+}
+
+#[tonic::async_trait]
+impl PointerService for MockPointerService {
+    async fn create_pointer(&self, _request: Request<CreatePointerRequest>) -> Result<Response<PointerResponse>, Status> {
+        Err(Status::unimplemented("not needed for this test"))
+    }
+
+    async fn update_pointer(&self, request: Request<UpdatePointerRequest>) -> Result<Response<PointerResponse>, Status> {
+        if let Some((name, content)) = self.interloper.lock().unwrap().take() {
+            self.write_elsewhere(&name, &content);
+        }
+        let request = request.into_inner();
+        POINTER_DATA_KEYS.lock().unwrap().push(request.data_key);
+        let update = request.pointer.ok_or_else(|| Status::invalid_argument("pointer is required"))?;
+        let mut pointer = self.pointer.lock().unwrap();
+        if update.counter != Some(pointer.counter.unwrap_or(0) + 1) {
+            return Err(Status::aborted("stale pointer counter"));
         }
+        pointer.content = update.content;
+        pointer.counter = update.counter;
+        Ok(Response::new(PointerResponse { pointer: Some(pointer.clone()) }))
+    }
+
+    async fn get_pointer(&self, request: Request<GetPointerRequest>) -> Result<Response<PointerResponse>, Status> {
+        POINTER_DATA_KEYS.lock().unwrap().push(request.into_inner().data_key);
+        let pointer = self.pointer.lock().unwrap().clone();
+        Ok(Response::new(PointerResponse { pointer: Some(pointer) }))
     }
 }
 
 async fn start_mock_grpc() -> (String, JoinHandle<()>) {
+    let (endpoint, handle, _) = start_mock_grpc_with_pointer().await;
+    (endpoint, handle)
+}
+
+async fn start_mock_grpc_with_pointer() -> (String, JoinHandle<()>, Arc<MockPointerService>) {
     let std_listener = TcpListener::bind("127.0.0.1:0").expect("bind mock grpc");
     std_listener.set_nonblocking(true).expect("nonblocking");
     let addr = std_listener.local_addr().unwrap();
     let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(std_listener).unwrap());
 
-    let svc = ArchiveServiceServer::new(MockArchiveService);
+    let archives = Arc::new(MockArchiveService::new());
+    let pointers = Arc::new(MockPointerService::new(archives.clone()));
+    let svc = ArchiveServiceServer::from_arc(archives);
+    let pointer_svc = PointerServiceServer::from_arc(pointers.clone());
     let handle = tokio::spawn(async move {
         tonic::transport::Server::builder()
             .add_service(svc)
+            .add_service(pointer_svc)
             .serve_with_incoming(incoming)
             .await
             .unwrap();
     });
 
-    (format!("http://{}", addr), handle)
+    (format!("http://{}", addr), handle, pointers)
 }
 
 fn start_ftp_server(archive: &str, addr: &str) -> std::thread::JoinHandle<()> {
@@ -164,7 +347,7 @@ async fn integration_list_and_get() {
 
     // 3) Start FTP server
     let _ftp_handle = start_ftp_server(
-        "cec7a9eb2c644b9a5de58bbcdf2e893db9f0b2acd7fc563fc849e19d1f6bd872",
+        INITIAL_ADDRESS,
         &ftp_addr_str,
     );
 
@@ -205,9 +388,8 @@ async fn integration_put_and_mkd() {
     let ftp_addr_str = format!("{}:{}", ftp_addr.ip(), ftp_addr.port());
 
     // 3) Start FTP server
-    let initial_address = "cec7a9eb2c644b9a5de58bbcdf2e893db9f0b2acd7fc563fc849e19d1f6bd872";
     let _ftp_handle = start_ftp_server(
-        initial_address,
+        INITIAL_ADDRESS,
         &ftp_addr_str,
     );
 
@@ -243,9 +425,8 @@ async fn integration_del_and_rmd() {
     let ftp_addr_str = format!("{}:{}", ftp_addr.ip(), ftp_addr.port());
 
     // 3) Start FTP server
-    let initial_address = "cec7a9eb2c644b9a5de58bbcdf2e893db9f0b2acd7fc563fc849e19d1f6bd872";
     let _ftp_handle = start_ftp_server(
-        initial_address,
+        INITIAL_ADDRESS,
         &ftp_addr_str,
     );
 
@@ -263,4 +444,699 @@ async fn integration_del_and_rmd() {
     ftp_stream.rmdir("dir").await.expect("rmdir");
 
     ftp_stream.quit().await.ok();
-}
\ No newline at end of file
+}
+fn start_ftp_server_with_config(archive: &str, pointer_name: Option<String>, addr: &str, config: AnttpConfig) -> std::thread::JoinHandle<()> {
+    let server = libunftp::Server::with_anttp_config(archive, pointer_name, config)
+        .greeting("Welcome to ANT FTP server")
+        .passive_ports(50000..=65535)
+        .build()
+        .unwrap();
+    let addr = addr.to_string();
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            server.listen(&addr).await.unwrap();
+        });
+    })
+}
+
+async fn connect_ftp() -> AsyncFtpStream {
+    connect_ftp_with_config(AnttpConfig::default()).await
+}
+
+async fn connect_ftp_with_config(config: AnttpConfig) -> AsyncFtpStream {
+    let ftp_addr_str = start_servers(config).await;
+    login(&ftp_addr_str).await
+}
+
+/// Starts the mock gRPC service and an FTP server backed by it, returning the FTP address.
+async fn start_servers(config: AnttpConfig) -> String {
+    start_servers_with_pointer(config, None).await.0
+}
+
+async fn start_servers_with_pointer(config: AnttpConfig, pointer_name: Option<String>) -> (String, Arc<MockPointerService>) {
+    let (grpc_endpoint, _grpc_handle, pointers) = start_mock_grpc_with_pointer().await;
+    unsafe { std::env::set_var("ANTTP_GRPC_ENDPOINT", &grpc_endpoint); }
+
+    let ftp_listener = TcpListener::bind("127.0.0.1:0").expect("bind ftp");
+    let ftp_addr = ftp_listener.local_addr().unwrap();
+    drop(ftp_listener); // release so libunftp can bind
+    let ftp_addr_str = format!("{}:{}", ftp_addr.ip(), ftp_addr.port());
+    let _ftp_handle = start_ftp_server_with_config(INITIAL_ADDRESS, pointer_name, &ftp_addr_str, config);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    (ftp_addr_str, pointers)
+}
+
+async fn login(ftp_addr_str: &str) -> AsyncFtpStream {
+    let mut ftp_stream = AsyncFtpStream::connect(ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("anonymous", "anonymous").await.expect("login");
+    ftp_stream
+}
+
+async fn retr(ftp_stream: &mut AsyncFtpStream, path: &str) -> Vec<u8> {
+    use async_std::io::ReadExt as _;
+    let mut stream = ftp_stream.retr_as_stream(path).await.expect("retr_as_stream");
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.expect("read_to_end");
+    ftp_stream.finalize_retr_stream(stream).await.expect("finalize retr");
+    data
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_rename() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // Rename a file
+    ftp_stream.rename("file1.txt", "renamed.txt").await.expect("rename file");
+    let list = ftp_stream.nlst(None).await.expect("nlst");
+    assert!(list.iter().any(|item| item == "renamed.txt"));
+    assert!(!list.iter().any(|item| item == "file1.txt"));
+    assert_eq!(retr(&mut ftp_stream, "renamed.txt").await, b"hello world");
+
+    // Rename a directory along with its contents
+    ftp_stream.mkdir("photos").await.expect("mkdir");
+    let mut reader = b"photo".as_slice();
+    ftp_stream.put_file("photos/a.jpg", &mut reader).await.expect("put_file");
+    ftp_stream.rename("photos", "pictures").await.expect("rename dir");
+    let list = ftp_stream.nlst(None).await.expect("nlst");
+    assert!(list.iter().any(|item| item == "pictures"));
+    assert!(!list.iter().any(|item| item == "photos"));
+    assert_eq!(retr(&mut ftp_stream, "pictures/a.jpg").await, b"photo");
+
+    // Neither a file nor a directory may replace a directory, and a directory can't replace a file
+    let err = ftp_stream.rename("renamed.txt", "dir").await.unwrap_err();
+    assert!(err.to_string().contains("553"), "{}", err);
+    let err = ftp_stream.rename("pictures", "renamed.txt").await.unwrap_err();
+    assert!(err.to_string().contains("553"), "{}", err);
+    let err = ftp_stream.rename("pictures", "renamed.txt/photos").await.unwrap_err();
+    assert!(err.to_string().contains("553"), "{}", err);
+    assert_eq!(retr(&mut ftp_stream, "renamed.txt").await, b"hello world");
+    assert_eq!(retr(&mut ftp_stream, "pictures/a.jpg").await, b"photo");
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_rename_directory_over_message_limit() {
+    let config = AnttpConfig {
+        max_encoding_message_size: 1024,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+
+    // Each file fits in a message, but all of them together don't
+    for name in ["a.bin", "b.bin", "c.bin"] {
+        let mut reader = [7u8; 600].as_slice();
+        ftp_stream.put_file(format!("big/{}", name), &mut reader).await.expect("put_file");
+    }
+    ftp_stream.rename("big", "moved").await.expect("rename dir");
+    for name in ["a.bin", "b.bin", "c.bin"] {
+        assert_eq!(retr(&mut ftp_stream, &format!("moved/{}", name)).await, [7u8; 600]);
+    }
+    assert!(!ftp_stream.nlst(None).await.expect("nlst").iter().any(|item| item == "big"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_resume_get() {
+    let mut ftp_stream = connect_ftp().await;
+
+    ftp_stream.resume_transfer(6).await.expect("rest");
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"world");
+
+    // The offset only applies to the next transfer
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_resume_and_append_put() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // Resume an interrupted upload, replacing everything after the offset
+    ftp_stream.resume_transfer(6).await.expect("rest");
+    let mut reader = b"there".as_slice();
+    ftp_stream.put_file("file1.txt", &mut reader).await.expect("put_file");
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello there");
+
+    // Append to the end of the file
+    let mut reader = b"!".as_slice();
+    ftp_stream.append_file("file1.txt", &mut reader).await.expect("append_file");
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello there!");
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_message_size_limit() {
+    let config = AnttpConfig {
+        max_decoding_message_size: 8,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+
+    // file1.txt is larger than the decode limit
+    let stream = ftp_stream.retr_as_stream("file1.txt").await.expect("retr_as_stream");
+    let result = ftp_stream.finalize_retr_stream(stream).await;
+    assert!(result.unwrap_err().to_string().contains("552"));
+    ftp_stream.quit().await.ok();
+
+    let config = AnttpConfig {
+        max_encoding_message_size: 128,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+    let mut reader = [0u8; 256].as_slice();
+    let result = ftp_stream.put_file("large.txt", &mut reader).await;
+    assert!(result.unwrap_err().to_string().contains("552"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_size_and_mdtm() {
+    let mut ftp_stream = connect_ftp().await;
+    CONTENT_READS.store(0, Ordering::SeqCst);
+
+    assert_eq!(ftp_stream.size("file1.txt").await.expect("size"), 11);
+    let modified = ftp_stream.mdtm("file1.txt").await.expect("mdtm");
+    assert_eq!(modified.to_string(), "2023-11-14 22:13:20");
+    assert!(ftp_stream.size("missing.txt").await.is_err());
+
+    // Metadata comes from the parent listing, never from the file itself
+    assert_eq!(CONTENT_READS.load(Ordering::SeqCst), 0);
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_directory_detection() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // An existing directory and a newly created one are both directories, not empty files
+    ftp_stream.mkdir("new_dir").await.expect("mkdir");
+    for dir in ["dir", "new_dir"] {
+        let facts = ftp_stream.mlst(Some(dir)).await.expect("mlst");
+        assert!(facts.to_string().contains("type=dir"), "{} reported as {}", dir, facts);
+    }
+    let facts = ftp_stream.mlst(Some("file1.txt")).await.expect("mlst");
+    assert!(facts.to_string().contains("type=file"));
+
+    // DELE only removes files and RMD only removes directories
+    assert!(ftp_stream.rm("dir").await.is_err());
+    assert!(ftp_stream.rmdir("file1.txt").await.is_err());
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_placeholders_hidden() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // The placeholder that keeps `dir` alive is never shown or served
+    assert!(ftp_stream.nlst(Some("dir")).await.expect("nlst").is_empty());
+    assert!(ftp_stream.mlsd(Some("dir")).await.expect("mlsd").is_empty());
+    assert!(ftp_stream.rm("dir/.metadata").await.is_err());
+    let stream = ftp_stream.retr_as_stream("dir/.metadata").await.expect("retr_as_stream");
+    assert!(ftp_stream.finalize_retr_stream(stream).await.is_err());
+
+    // A real file replaces the placeholder, and deleting it again keeps the directory
+    let mut reader = b"content".as_slice();
+    ftp_stream.put_file("dir/a.txt", &mut reader).await.expect("put_file");
+    assert_eq!(ftp_stream.nlst(Some("dir")).await.expect("nlst").len(), 1);
+    ftp_stream.rm("dir/a.txt").await.expect("rm");
+    let facts = ftp_stream.mlst(Some("dir")).await.expect("mlst");
+    assert!(facts.to_string().contains("type=dir"));
+    assert!(ftp_stream.nlst(Some("dir")).await.expect("nlst").is_empty());
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_cwd() {
+    let mut ftp_stream = connect_ftp().await;
+
+    ftp_stream.cwd("dir").await.expect("cwd into directory");
+    assert_eq!(ftp_stream.pwd().await.expect("pwd"), "/dir");
+    ftp_stream.cdup().await.expect("cdup");
+
+    // Missing paths and files are rejected, leaving the working directory unchanged
+    assert!(ftp_stream.cwd("nonexistent").await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.cwd("file1.txt").await.unwrap_err().to_string().contains("550"));
+    assert_eq!(ftp_stream.pwd().await.expect("pwd"), "/");
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_rmd_non_empty() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // Non-empty directories are kept unless recursive removal is enabled
+    let mut reader = b"content".as_slice();
+    ftp_stream.put_file("dir/a.txt", &mut reader).await.expect("put_file");
+    assert!(ftp_stream.rmdir("dir").await.unwrap_err().to_string().contains("550"));
+    assert_eq!(ftp_stream.nlst(Some("dir")).await.expect("nlst").len(), 1);
+    ftp_stream.quit().await.ok();
+
+    let config = AnttpConfig {
+        recursive_rmd: true,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+    ftp_stream.mkdir("dir/sub").await.expect("mkdir");
+    let mut reader = b"content".as_slice();
+    ftp_stream.put_file("dir/sub/a.txt", &mut reader).await.expect("put_file");
+    ftp_stream.rmdir("dir").await.expect("rmdir");
+    let list = ftp_stream.nlst(None).await.expect("nlst");
+    assert!(!list.iter().any(|item| item == "dir"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_mkdir_parents() {
+    let mut ftp_stream = connect_ftp().await;
+
+    // Intermediate directories are created and listed as directories
+    ftp_stream.mkdir("a/b/c").await.expect("mkdir");
+    assert_eq!(ftp_stream.nlst(Some("a")).await.expect("nlst"), vec!["b"]);
+    assert!(ftp_stream.mlst(Some("a/b")).await.expect("mlst").to_string().contains("type=dir"));
+    ftp_stream.mkdir("a/b").await.expect("mkdir existing directory");
+
+    let mut reader = b"content".as_slice();
+    ftp_stream.put_file("x/y/z.txt", &mut reader).await.expect("put_file");
+    assert!(ftp_stream.mlst(Some("x/y")).await.expect("mlst").to_string().contains("type=dir"));
+    assert_eq!(retr(&mut ftp_stream, "x/y/z.txt").await, b"content");
+
+    // Directories and files can't replace each other
+    assert!(ftp_stream.mkdir("file1.txt").await.is_err());
+    assert!(ftp_stream.mkdir("file1.txt/sub").await.is_err());
+    let mut reader = b"content".as_slice();
+    assert!(ftp_stream.put_file("dir", &mut reader).await.is_err());
+    let mut reader = b"content".as_slice();
+    assert!(ftp_stream.put_file("file1.txt/a.txt", &mut reader).await.is_err());
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_shared_sessions() {
+    let ftp_addr_str = start_servers(AnttpConfig::default()).await;
+    let mut first = login(&ftp_addr_str).await;
+    let mut second = login(&ftp_addr_str).await;
+
+    // A write in one session is visible in another
+    let mut reader = b"first".as_slice();
+    first.put_file("first.txt", &mut reader).await.expect("put_file");
+    assert_eq!(retr(&mut second, "first.txt").await, b"first");
+
+    // Concurrent writes are serialised, so neither is lost
+    let mut first_reader = b"a".as_slice();
+    let mut second_reader = b"b".as_slice();
+    let (a, b) = tokio::join!(
+        first.put_file("a.txt", &mut first_reader),
+        second.put_file("b.txt", &mut second_reader),
+    );
+    a.expect("put_file");
+    b.expect("put_file");
+    let list = first.nlst(None).await.expect("nlst");
+    assert!(list.iter().any(|item| item == "a.txt"));
+    assert!(list.iter().any(|item| item == "b.txt"));
+
+    first.quit().await.ok();
+    second.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_pointer_moved_between_writes() {
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(AnttpConfig::default(), Some(POINTER_NAME.to_string())).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+
+    // Another instance moves the pointer; our write lands on top of its archive
+    pointers.write_elsewhere("remote.txt", b"remote");
+    let mut reader = b"local".as_slice();
+    ftp_stream.put_file("local.txt", &mut reader).await.expect("put_file");
+
+    let files = pointers.files();
+    assert_eq!(files.get("remote.txt").map(Vec::as_slice), Some(b"remote".as_slice()));
+    assert_eq!(files.get("local.txt").map(Vec::as_slice), Some(b"local".as_slice()));
+    assert_eq!(pointers.pointer.lock().unwrap().counter, Some(2));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_pointer_without_counter() {
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(AnttpConfig::default(), Some(POINTER_NAME.to_string())).await;
+    pointers.pointer.lock().unwrap().counter = None;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+
+    // A pointer that never had a counter is still only moved from the head it was read at
+    let mut reader = b"local".as_slice();
+    ftp_stream.put_file("local.txt", &mut reader).await.expect("put_file");
+    assert_eq!(pointers.pointer.lock().unwrap().counter, Some(1));
+    assert!(pointers.files().contains_key("local.txt"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_pointer_moved_during_write() {
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(AnttpConfig::default(), Some(POINTER_NAME.to_string())).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+
+    // The pointer moves while the write is in flight, so it is re-applied on the new head
+    *pointers.interloper.lock().unwrap() = Some(("remote.txt".to_string(), b"remote".to_vec()));
+    let mut reader = b"local".as_slice();
+    ftp_stream.put_file("local.txt", &mut reader).await.expect("put_file");
+
+    let files = pointers.files();
+    assert!(files.contains_key("remote.txt"));
+    assert!(files.contains_key("local.txt"));
+    assert_eq!(pointers.pointer.lock().unwrap().counter, Some(2));
+    let list = ftp_stream.nlst(None).await.expect("nlst");
+    assert!(list.iter().any(|item| item == "remote.txt"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_conflict_policy() {
+    // Both writers change local.txt, so ours is kept alongside theirs
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(AnttpConfig::default(), Some(POINTER_NAME.to_string())).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+    *pointers.interloper.lock().unwrap() = Some(("local.txt".to_string(), b"remote".to_vec()));
+    let mut reader = b"local".as_slice();
+    ftp_stream.put_file("local.txt", &mut reader).await.expect("put_file");
+
+    let files = pointers.files();
+    assert_eq!(files.get("local.txt").map(Vec::as_slice), Some(b"remote".as_slice()));
+    let conflict = format!("local.txt.conflict-{}", MODIFIED);
+    assert_eq!(files.get(&conflict).map(Vec::as_slice), Some(b"local".as_slice()));
+    ftp_stream.quit().await.ok();
+
+    // Rejecting conflicts fails the write and leaves their change in place
+    let config = AnttpConfig {
+        conflict_policy: ConflictPolicy::Reject,
+        ..Default::default()
+    };
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(config, Some(POINTER_NAME.to_string())).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+    *pointers.interloper.lock().unwrap() = Some(("local.txt".to_string(), b"remote".to_vec()));
+    let mut reader = b"local".as_slice();
+    assert!(ftp_stream.put_file("local.txt", &mut reader).await.unwrap_err().to_string().contains("450"));
+    assert_eq!(pointers.files().get("local.txt").map(Vec::as_slice), Some(b"remote".as_slice()));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_anttp_unavailable() {
+    // Nothing listens on this endpoint, as if AntTP were restarting
+    let grpc_listener = TcpListener::bind("127.0.0.1:0").expect("bind grpc");
+    let grpc_addr = grpc_listener.local_addr().unwrap();
+    drop(grpc_listener);
+    unsafe { std::env::set_var("ANTTP_GRPC_ENDPOINT", format!("http://{}", grpc_addr)); }
+
+    let ftp_listener = TcpListener::bind("127.0.0.1:0").expect("bind ftp");
+    let ftp_addr = ftp_listener.local_addr().unwrap();
+    drop(ftp_listener); // release so libunftp can bind
+    let ftp_addr_str = format!("{}:{}", ftp_addr.ip(), ftp_addr.port());
+    let _ftp_handle = start_ftp_server_with_config(INITIAL_ADDRESS, None, &ftp_addr_str, AnttpConfig::default());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+
+    // Outages are reported as transient rather than as missing files
+    assert!(ftp_stream.size("file1.txt").await.unwrap_err().to_string().contains("450"));
+    assert!(ftp_stream.cwd("dir").await.unwrap_err().to_string().contains("450"));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_retry_transient_failures() {
+    let config = AnttpConfig {
+        retry_initial_backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+
+    // A brief outage is retried transparently
+    UNAVAILABLE_READS.store(2, Ordering::SeqCst);
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+    ftp_stream.quit().await.ok();
+
+    // Without retries the outage reaches the client as a transient error
+    let config = AnttpConfig {
+        retry_attempts: 0,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+    UNAVAILABLE_READS.store(1, Ordering::SeqCst);
+    assert!(ftp_stream.size("file1.txt").await.unwrap_err().to_string().contains("450"));
+    UNAVAILABLE_READS.store(0, Ordering::SeqCst);
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_read_timeout() {
+    let config = AnttpConfig {
+        read_timeout: Duration::from_millis(100),
+        retry_attempts: 0,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+
+    // A hung AntTP call is abandoned and reported as transient
+    SLOW_READS.store(1, Ordering::SeqCst);
+    assert!(ftp_stream.size("file1.txt").await.unwrap_err().to_string().contains("450"));
+    SLOW_READS.store(0, Ordering::SeqCst);
+    assert_eq!(ftp_stream.size("file1.txt").await.expect("size"), 11);
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_store_type() {
+    let config = AnttpConfig {
+        store_type: StoreType::Memory,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+    WRITE_STORE_TYPES.lock().unwrap().clear();
+
+    let mut reader = b"content".as_slice();
+    ftp_stream.put_file("memory.txt", &mut reader).await.expect("put_file");
+    ftp_stream.rm("memory.txt").await.expect("rm");
+
+    let store_types = WRITE_STORE_TYPES.lock().unwrap().clone();
+    assert!(!store_types.is_empty());
+    assert!(store_types.iter().all(|store_type| store_type.as_deref() == Some("memory")));
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_read_store_fallback() {
+    // The archive is missing from the disk store, so reads fail without a fallback
+    let mut ftp_stream = connect_ftp().await;
+    *NETWORK_ONLY.lock().unwrap() = vec![INITIAL_ADDRESS.to_string()];
+    assert!(ftp_stream.size("file1.txt").await.unwrap_err().to_string().contains("550"));
+    ftp_stream.quit().await.ok();
+
+    let config = AnttpConfig {
+        read_store_types: vec![StoreType::Disk, StoreType::Network],
+        warm_reads: true,
+        ..Default::default()
+    };
+    let mut ftp_stream = connect_ftp_with_config(config).await;
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+
+    // The archive is copied to the disk store in the background
+    for _ in 0..50 {
+        if NETWORK_ONLY.lock().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(NETWORK_ONLY.lock().unwrap().is_empty());
+
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_read_only() {
+    let config = AnttpConfig {
+        read_only: true,
+        ..Default::default()
+    };
+    let (ftp_addr_str, pointers) = start_servers_with_pointer(config, Some(POINTER_NAME.to_string())).await;
+    let mut ftp_stream = login(&ftp_addr_str).await;
+
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+    let mut reader = b"content".as_slice();
+    assert!(ftp_stream.put_file("new.txt", &mut reader).await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.rm("file1.txt").await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.mkdir("dir").await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.rename("file1.txt", "file3.txt").await.unwrap_err().to_string().contains("550"));
+
+    // Listings carry no write permissions
+    let lines = ftp_stream.list(None).await.expect("list");
+    assert!(!lines.is_empty());
+    assert!(lines.iter().all(|line| !line[..10].contains('w')), "{:?}", lines);
+
+    assert_eq!(pointers.pointer.lock().unwrap().counter, Some(0));
+    ftp_stream.quit().await.ok();
+}
+
+/// Starts the mock AntTP and an FTP server that only lets in the `USERS`.
+async fn start_servers_with_users() -> (String, Arc<MockPointerService>) {
+    let (grpc_endpoint, _grpc_handle, pointers) = start_mock_grpc_with_pointer().await;
+    unsafe { std::env::set_var("ANTTP_GRPC_ENDPOINT", &grpc_endpoint); }
+
+    let ftp_listener = TcpListener::bind("127.0.0.1:0").expect("bind ftp");
+    let ftp_addr = ftp_listener.local_addr().unwrap();
+    drop(ftp_listener); // release so libunftp can bind
+    let ftp_addr_str = format!("{}:{}", ftp_addr.ip(), ftp_addr.port());
+    let users = Arc::new(UserFile::from_json(USERS).unwrap());
+    let server = libunftp::Server::with_anttp_users(INITIAL_ADDRESS, None, AnttpConfig::default(), users)
+        .passive_ports(50000..=65535)
+        .build()
+        .unwrap();
+    let addr = ftp_addr_str.clone();
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            server.listen(&addr).await.unwrap();
+        });
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    (ftp_addr_str, pointers)
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_users_file() {
+    let (ftp_addr_str, _) = start_servers_with_users().await;
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    assert!(ftp_stream.login("alice", "wrong").await.is_err());
+    assert!(ftp_stream.login("anonymous", "anonymous").await.is_err());
+    ftp_stream.login("alice", "secret").await.expect("login");
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_user_permissions() {
+    let (ftp_addr_str, _) = start_servers_with_users().await;
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("bot", "secret").await.expect("login");
+
+    let mut reader = b"ingested".as_slice();
+    ftp_stream.put_file("ingest.txt", &mut reader).await.expect("put_file");
+    let mut reader = b"again".as_slice();
+    assert!(ftp_stream.put_file("ingest.txt", &mut reader).await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.list(None).await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.rm("ingest.txt").await.unwrap_err().to_string().contains("550"));
+    assert!(ftp_stream.mkdir("dir").await.unwrap_err().to_string().contains("550"));
+    // Nor can missing directories be created by uploading into them
+    let mut reader = b"nested".as_slice();
+    assert!(ftp_stream.put_file("a/b/c/x.txt", &mut reader).await.unwrap_err().to_string().contains("550"));
+    let mut reader = b"in dir".as_slice();
+    ftp_stream.put_file("dir/in-dir.txt", &mut reader).await.expect("put_file into an existing directory");
+    ftp_stream.quit().await.ok();
+
+    // Appending needs the size of the file, but not the permission to read it
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("appender", "secret").await.expect("login");
+    let mut reader = b" more".as_slice();
+    ftp_stream.append_file("ingest.txt", &mut reader).await.expect("append_file");
+    ftp_stream.quit().await.ok();
+
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("alice", "secret").await.expect("login");
+    assert_eq!(retr(&mut ftp_stream, "ingest.txt").await, b"ingested more");
+    ftp_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_user_archive() {
+    let (ftp_addr_str, pointers) = start_servers_with_users().await;
+    POINTER_DATA_KEYS.lock().unwrap().clear();
+
+    let mut team_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    team_stream.login("team", "secret").await.expect("login");
+    let mut reader = b"team content".as_slice();
+    team_stream.put_file("team.txt", &mut reader).await.expect("put_file");
+    assert_eq!(retr(&mut team_stream, "team.txt").await, b"team content");
+    assert_eq!(pointers.files().get("team.txt").map(Vec::as_slice), Some(b"team content".as_slice()));
+    let data_keys = POINTER_DATA_KEYS.lock().unwrap().clone();
+    assert!(!data_keys.is_empty());
+    assert!(data_keys.iter().all(|data_key| data_key.as_deref() == Some("team-key")));
+
+    // Users without an archive of their own are still served the server's
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("alice", "secret").await.expect("login");
+    assert!(ftp_stream.size("team.txt").await.unwrap_err().to_string().contains("550"));
+    assert_eq!(retr(&mut ftp_stream, "file1.txt").await, b"hello world");
+
+    ftp_stream.quit().await.ok();
+    team_stream.quit().await.ok();
+}
+
+#[tokio::test]
+#[serial]
+async fn integration_user_home() {
+    let (ftp_addr_str, _) = start_servers_with_users().await;
+
+    // The home directory is created on login
+    let mut partner_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    partner_stream.login("partner", "secret").await.expect("login");
+    assert!(partner_stream.nlst(None).await.expect("nlst").is_empty());
+    let mut reader = b"partner content".as_slice();
+    partner_stream.put_file("report.txt", &mut reader).await.expect("put_file");
+    assert_eq!(partner_stream.nlst(None).await.expect("nlst"), vec!["report.txt"]);
+
+    // There is no way out of the home directory
+    assert!(partner_stream.size("../../file1.txt").await.unwrap_err().to_string().contains("550"));
+    partner_stream.cwd("..").await.expect("cwd");
+    assert_eq!(partner_stream.nlst(None).await.expect("nlst"), vec!["report.txt"]);
+    assert!(partner_stream.rmdir("/").await.unwrap_err().to_string().contains("550"));
+    assert!(partner_stream.rename("report.txt", "..").await.unwrap_err().to_string().contains("553"));
+
+    let mut ftp_stream = AsyncFtpStream::connect(&ftp_addr_str).await.expect("connect ftp");
+    ftp_stream.login("alice", "secret").await.expect("login");
+    assert_eq!(retr(&mut ftp_stream, "partners/acme/report.txt").await, b"partner content");
+
+    ftp_stream.quit().await.ok();
+    partner_stream.quit().await.ok();
+}